@@ -0,0 +1,105 @@
+//! English and Dutch auctions, kept in the seller chain's `listings`. Bids are escrowed on
+//! the bidder chain like offers, and once `Operation::CloseAuction` runs the winning bid is
+//! settled through the same messages: the token goes out with `SafeTransferFrom` and the
+//! escrow is paid when it arrived.
+
+use crate::{state::Aqueduct, Error};
+use aqueduct::{Auction, AuctionKind, Bid, Listing, Message, TokenId};
+use linera_sdk::{
+    base::{Amount, ChainId, Timestamp},
+    contract::system_api,
+    ExecutionResult,
+};
+use log::info;
+
+impl Aqueduct {
+    /// Seller side of `Operation::ListAuction`, starting now.
+    pub(crate) async fn list_auction(
+        &mut self,
+        token_id: TokenId,
+        kind: AuctionKind,
+        reserve: Amount,
+        end: Timestamp,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        if !self.check_own(token_id.clone()).await? {
+            return Err(Error::ThisChainDoesNotOwnThisTokenError);
+        }
+        self.ensure_no_bids(&token_id).await?;
+        let listing = Listing::Auction(Auction::new(kind, reserve, system_api::current_system_time(), end)?);
+        let message = Message::MirrorListing { token_id: token_id.clone(), price: Some(listing.price()) };
+        self.listings.insert(&token_id, listing)?;
+        Ok(ExecutionResult::default().with_authenticated_message(token_id.minted_chain, message))
+    }
+
+    /// Bidder side of `Operation::Bid`.
+    pub(crate) async fn bid(
+        &mut self,
+        token_id: TokenId,
+        seller: ChainId,
+        amount: Amount,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        self.escrow_add(&token_id, amount).await?;
+        Ok(ExecutionResult::default().with_authenticated_message(seller, Message::PlaceBid { token_id, amount }))
+    }
+
+    /// Seller side of `Operation::Bid`: a rejected or outbid bid is refunded.
+    pub(crate) async fn place_bid(&mut self, token_id: TokenId, bid: Bid) -> Result<ExecutionResult<Message>, Error> {
+        let mut auction = match self.listings.get(&token_id).await? {
+            Some(Listing::Auction(auction)) => auction,
+            _ => {
+                info!("{:?} is not auctioned, bid refunded", token_id);
+                return Ok(Self::refund_bid(token_id, bid));
+            }
+        };
+        match auction.place(bid.clone(), system_api::current_system_time()) {
+            Ok(outbid) => {
+                self.listings.insert(&token_id, Listing::Auction(auction))?;
+                Ok(match outbid {
+                    Some(outbid) => Self::refund_bid(token_id, outbid),
+                    None => ExecutionResult::default(),
+                })
+            }
+            Err(error) => {
+                info!("bid on {:?} refunded: {}", token_id, error);
+                Ok(Self::refund_bid(token_id, bid))
+            }
+        }
+    }
+
+    /// Seller side of `Operation::CloseAuction`: the winning bid, if any, is settled like an
+    /// accepted offer.
+    pub(crate) async fn close_auction(&mut self, token_id: TokenId) -> Result<ExecutionResult<Message>, Error> {
+        let auction = match self.listings.get(&token_id).await? {
+            Some(Listing::Auction(auction)) => auction,
+            _ => return Err(Error::ListingNotFound),
+        };
+        let winner = auction.close(system_api::current_system_time())?;
+        self.listings.remove(&token_id)?;
+        let mirror = Message::MirrorListing { token_id: token_id.clone(), price: None };
+        let result = ExecutionResult::default().with_authenticated_message(token_id.minted_chain, mirror);
+        Ok(match winner {
+            Some(winner) => {
+                let message = Message::SettleEscrow {
+                    token_id,
+                    seller: system_api::current_chain_id(),
+                    amount: winner.amount,
+                };
+                result.with_authenticated_message(winner.bidder, message)
+            }
+            None => result,
+        })
+    }
+
+    /// Refuses to drop or replace a listing whose auction holds someone's escrowed bid.
+    pub(crate) async fn ensure_no_bids(&mut self, token_id: &TokenId) -> Result<(), Error> {
+        match self.listings.get(token_id).await? {
+            Some(Listing::Auction(Auction { best_bid: Some(_), .. })) => Err(Error::AuctionHasBidsError),
+            _ => Ok(()),
+        }
+    }
+
+    fn refund_bid(token_id: TokenId, bid: Bid) -> ExecutionResult<Message> {
+        let message = Message::RefundEscrow { token_id, amount: bid.amount };
+        ExecutionResult::default().with_authenticated_message(bid.bidder, message)
+    }
+}
@@ -0,0 +1,73 @@
+//! The legs of `Operation::Buy` that move value. The buyer escrows the price it was quoted,
+//! the seller only lets the token go while its listing still asks that price, and the escrow
+//! is paid out once the buyer chain accepted the token. A failing leg sends the flow back
+//! through the compensation of every leg before it, ending with the escrow refunded.
+
+use crate::{state::Aqueduct, Error};
+use aqueduct::{Compensation, FlowKind, Listing, OnERC721Received, Token, TokenId, M4};
+use bcs::to_bytes;
+use linera_sdk::{
+    base::{Amount, ChainId},
+    contract::system_api,
+};
+
+impl Aqueduct {
+    /// Buyer side, once the seller quoted `price`: escrows it for as long as the flow is pending.
+    pub(crate) async fn escrow_payment(&mut self, m4: &M4, token_id: &TokenId, price: Amount) -> Result<(), Error> {
+        self.ensure_pending(m4).await?;
+        self.escrow_add(token_id, price).await?;
+        let compensation = Compensation::RefundEscrow { token_id: token_id.clone(), amount: price };
+        self.record_compensation(m4, FlowKind::Buy, compensation).await
+    }
+
+    /// Seller side: gives the token up, as long as it is still listed at `price`. The seller
+    /// can take it back with `Operation::RecoverFlow` until the minting chain records the sale.
+    pub(crate) async fn sell(&mut self, m4: &M4, token_id: TokenId, price: Amount) -> Result<Token, Error> {
+        if self.get_price(token_id.clone()).await? != price {
+            return Err(Error::PriceChangedError);
+        }
+        let token = self.transfer_from_me(token_id).await?;
+        let compensation = Compensation::RestoreToken { token: token.clone() };
+        self.record_compensation(m4, FlowKind::Buy, compensation).await?;
+        Ok(token)
+    }
+
+    /// Buyer side, once the minting chain recorded the sale: keeps the token and pays the
+    /// escrowed price to `seller`. Nothing is kept or paid if the receiver refuses the token or
+    /// the escrow was already refunded by `Operation::RecoverFlow`.
+    pub(crate) async fn receive_bought(&mut self, m4: &M4, token: &Token, seller: ChainId, price: Amount) -> Result<(), Error> {
+        self.ensure_pending(m4).await?;
+        self.check_erc721_received(OnERC721Received {
+            from: seller,
+            to: system_api::current_chain_id(),
+            token_id: token.id.clone(),
+            data: to_bytes(&price)?,
+        })
+        .await?;
+        self.release_escrow(&token.id, price).await?;
+        self.credit(price).await?;
+        if let Err(error) = self.debit(price, seller).await {
+            self.escrow_add(&token.id, price).await?;
+            return Err(error);
+        }
+        self.transfer_to_me(token).await?;
+        self.drop_compensations(m4).await
+    }
+
+    /// Buyer side of a failed sale, the escrow being left alone if `Operation::RecoverFlow`
+    /// refunded it already.
+    pub(crate) async fn refund_payment(&mut self, m4: &M4, token_id: TokenId, price: Amount) -> Result<(), Error> {
+        if self.ensure_pending(m4).await.is_err() {
+            return Ok(());
+        }
+        self.refund_escrow(token_id, price).await?;
+        self.drop_compensations(m4).await
+    }
+
+    /// Seller side of a failed sale: the token comes back, listed at the price it was sold for.
+    pub(crate) async fn unsell(&mut self, token: &Token, price: Amount) -> Result<(), Error> {
+        self.transfer_to_me(token).await?;
+        self.listings.insert(&token.id, Listing::FixedPrice { price })?;
+        Ok(())
+    }
+}
@@ -1,18 +1,23 @@
 #![cfg_attr(target_arch = "wasm32", no_main)]
+mod auctions;
+mod buy;
+mod offers;
+mod recovery;
+mod revenue;
 mod state;
 
-use self::state::Aqueduct;
+use self::revenue::{RevenueBackend, RevenueSource};
+use self::state::{Aqueduct, SCHEMA_VERSION};
 use async_trait::async_trait;
 use linera_sdk::{
-    base::{SessionId, WithContractAbi, ChainId, Amount, Timestamp, ApplicationId},
+    base::{SessionId, WithContractAbi, ChainId, Amount, ApplicationId, Owner, Timestamp},
     contract::system_api,
     ApplicationCallResult, CalleeContext, Contract, MessageContext,
     ExecutionResult, OperationContext, SessionCallResult, ViewStateStorage,
 };
 use thiserror::Error;
-use serde::Serialize;
+use linera_views::views::View;
 use log::info;
-use bcs::{to_bytes, from_bytes};
 //use keccak_hash::keccak256;
 use aqueduct::*;
 //use uuid::Uuid;
@@ -36,6 +41,7 @@ impl Contract for Aqueduct {
         _context: &OperationContext,
         _argument: (),
     ) -> Result<ExecutionResult<Self::Message>, Self::Error> {
+        self.schema_version.set(SCHEMA_VERSION);
         Ok(ExecutionResult::default())
     }
 
@@ -45,13 +51,20 @@ impl Contract for Aqueduct {
         context: &OperationContext,
         operation: Operation,
     ) -> Result<ExecutionResult<Self::Message>, Self::Error> {
+        self.migrate().await?;
         match operation.clone() {
             Operation::Mint {
                 name,
                 mut description,
                 keywords,
                 segments,
+                royalty_bps,
             } => {
+                validate_schedule(&segments)?;
+                let royalty_bps = royalty_bps.unwrap_or(0);
+                if royalty_bps > MAX_ROYALTY_BPS {
+                    return Err(Error::RoyaltyTooHighError);
+                }
                 let stream_id = StreamId {
                     company: system_api::current_chain_id(),
                     //id: uuid::Uuid::new_v4().to_string(),
@@ -60,7 +73,7 @@ impl Contract for Aqueduct {
                 let stream = Stream {
                     keywords,
                     segments,
-                    created: system_api::current_system_time().micros(),
+                    created: system_api::current_system_time(),
                     milestones_received: 0,
                     periods_received: 0,
                 };
@@ -74,80 +87,238 @@ impl Contract for Aqueduct {
                 };
                 let num = *self.number_minted.get();
                 let id = TokenId { minted_chain: system_api::current_chain_id(), index: num };
-                self.owned_tokens.insert(&Token { id: id.clone(), metadata })?;
+                self.owned_tokens.insert(&id, metadata)?;
                 self.current_owner_minted.insert(&id, system_api::current_chain_id())?;
+                if royalty_bps > 0 {
+                    self.royalties.insert(&id, royalty_bps)?;
+                }
                 self.number_minted.set(num + 1);
                 Ok(ExecutionResult::default())
             },
-            Operation::Burn {
-                token_id,
-            } => {
-                let mut realtoken = Token {
-                    id: TokenId {
-                        minted_chain: system_api::current_chain_id(),
-                        index: 0,
-                    },
-                    metadata: TokenMetadata {
-                        name: "".to_string(),
-                        description: "".to_string(),
-                        image: "".to_string(),
-                    },
-                };
-                self.owned_tokens.for_each_index(|key| {
-                    if key.id == token_id {
-                        realtoken = key;
-                    }
-                    Ok(())
-                }).await?;
-                self.owned_tokens.remove(&realtoken)?;
-                self.listings.remove(&token_id)?;
-                Ok(ExecutionResult::default())
-            },
             Operation::List {
                 token_id,
                 amount,
             } => {
-                self.listings.insert(&token_id, amount)?;
-                Ok(ExecutionResult::default())
+                if !self.check_own(token_id.clone()).await? {
+                    return Err(Error::ThisChainDoesNotOwnThisTokenError);
+                }
+                self.ensure_no_bids(&token_id).await?;
+                self.listings.insert(&token_id, Listing::FixedPrice { price: amount })?;
+                let message = Message::MirrorListing { token_id: token_id.clone(), price: Some(amount) };
+                Ok(ExecutionResult::default().with_authenticated_message(token_id.minted_chain, message))
             },
             Operation::Cancel {
                 token_id,
             } => {
+                if self.listings.get(&token_id).await?.is_none() {
+                    return Err(Error::ListingNotFound);
+                }
+                self.ensure_no_bids(&token_id).await?;
                 self.listings.remove(&token_id)?;
-                Ok(ExecutionResult::default())
+                let message = Message::MirrorListing { token_id: token_id.clone(), price: None };
+                Ok(ExecutionResult::default().with_authenticated_message(token_id.minted_chain, message))
             },
             Operation::Income {
                 amount,
                 keyword,
             } => {
+                self.deposit_from(context.authenticated_signer, amount).await?;
                 self.credit(amount).await?;
+                let key = RevenueKey::at(keyword, system_api::current_system_time());
+                let mut revenue = self.revenue.get(&key).await?.unwrap_or_else(Amount::zero);
+                revenue.saturating_add_assign(amount);
+                self.revenue.insert(&key, revenue)?;
+                Ok(ExecutionResult::default())
+            },
+            Operation::Deposit {
+                amount,
+            } => {
+                if Self::fungible_id()?.is_some() {
+                    self.deposit_from(context.authenticated_signer, amount).await?;
+                    self.credit(amount).await?;
+                } else {
+                    let balance = native_deposit(self.balance().await, system_api::current_system_balance(), amount)
+                        .map_err(|_| Error::InsufficientBalanceError)?;
+                    self.balance.set(balance);
+                }
+                Ok(ExecutionResult::default())
+            },
+            Operation::RetireStream {
+                stream_id,
+            } => {
+                self.retire_stream(stream_id).await?;
+                Ok(ExecutionResult::default())
+            },
+            Operation::RegisterReceiver {
+                application_id,
+            } => {
+                self.receiver.set(application_id);
+                Ok(ExecutionResult::default())
+            },
+            Operation::Approve {
+                token_id,
+                spender,
+            } => {
+                if !self.check_own(token_id.clone()).await? {
+                    return Err(Error::ThisChainDoesNotOwnThisTokenError);
+                }
+                match spender {
+                    Some(spender) => self.token_approvals.insert(&token_id, spender)?,
+                    None => self.token_approvals.remove(&token_id)?,
+                }
+                Ok(ExecutionResult::default())
+            },
+            Operation::SetApprovalForAll {
+                operator,
+                approved,
+            } => {
+                if approved {
+                    self.operators.insert(&operator)?;
+                } else {
+                    self.operators.remove(&operator)?;
+                }
+                Ok(ExecutionResult::default())
+            },
+            Operation::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+            } => {
+                // no price attached, a failed transfer has nothing to pay back
+                let message = Message::SafeTransferFrom { from, to, token_id, data: Vec::new() };
+                Ok(ExecutionResult::default().with_authenticated_message(system_api::current_chain_id(), message))
+            },
+            Operation::Transfer {
+                token_id,
+                to,
+            } => {
+                let owner = system_api::current_chain_id();
+                if to == owner {
+                    return Err(Error::TransferToSelfError);
+                }
+                if !self.check_own(token_id.clone()).await? {
+                    return Err(Error::ThisChainDoesNotOwnThisTokenError);
+                }
+                self.ensure_no_bids(&token_id).await?;
+                let message = Message::SafeTransferFrom { from: owner, to, token_id, data: Vec::new() };
+                Ok(ExecutionResult::default().with_authenticated_message(owner, message))
+            },
+            Operation::MakeOffer {
+                token_id,
+                amount,
+                expires,
+            } => {
+                self.make_offer(token_id, amount, expires).await
+            },
+            Operation::ListAuction {
+                token_id,
+                kind,
+                reserve,
+                end,
+            } => {
+                self.list_auction(token_id, kind, reserve, end).await
+            },
+            Operation::Bid {
+                token_id,
+                seller,
+                amount,
+            } => {
+                self.bid(token_id, seller, amount).await
+            },
+            Operation::CloseAuction {
+                token_id,
+                seller,
+            } => {
+                if seller == system_api::current_chain_id() {
+                    self.close_auction(token_id).await
+                } else {
+                    Ok(ExecutionResult::default().with_authenticated_message(seller, Message::CloseAuction { token_id }))
+                }
+            },
+            Operation::AcceptOffer {
+                token_id,
+                amount,
+                bidder,
+            } => {
+                if !self.check_own(token_id.clone()).await? {
+                    return Err(Error::ThisChainDoesNotOwnThisTokenError);
+                }
+                self.ensure_no_bids(&token_id).await?;
+                let message = Message::AcceptOffer { token_id: token_id.clone(), amount, bidder };
+                Ok(ExecutionResult::default().with_authenticated_message(token_id.minted_chain, message))
+            },
+            Operation::WithdrawOffer {
+                token_id,
+                amount,
+            } => {
+                let escrow = self.escrow.get(&token_id).await?.unwrap_or_else(Amount::zero);
+                checked_debit(escrow, amount).map_err(|_| Error::NoEscrowForOfferError)?;
+                let message = Message::WithdrawOffer { token_id: token_id.clone(), amount };
+                Ok(ExecutionResult::default().with_authenticated_message(token_id.minted_chain, message))
+            },
+            Operation::Withdraw {
+                amount,
+                to,
+            } => {
+                self.withdraw(amount, to, context.authenticated_signer).await?;
                 Ok(ExecutionResult::default())
             },
+            Operation::RecoverFlow {
+                flow_id,
+            } => {
+                self.recover_flow(flow_id).await
+            },
 //m4 execute_operation start
         Operation::Buy { token_id } => {
+            let flow_id = self.start_flow(FlowKind::Buy, "Buy_GetOwner").await?;
+            let m4 = M4 {
+                original_chain: system_api::current_chain_id(),
+                original_operation: Some(operation),
+                original_ope_context: Some(context.clone()),
+                original_message: None,
+                original_msg_context: None,
+                flow_id,
+                step: 0,
+            };
+            let __message = Message::Buy_GetOwner { m4: m4.next() };
+            return Ok(ExecutionResult::default()
+                .with_authenticated_message(token_id.minted_chain, __message));
+        }
+        Operation::Burn { token_id } => {
+            let flow_id = self.start_flow(FlowKind::Burn, "Burn_Minted").await?;
             let m4 = M4 {
                 original_chain: system_api::current_chain_id(),
                 original_operation: Some(operation),
                 original_ope_context: Some(context.clone()),
                 original_message: None,
                 original_msg_context: None,
+                flow_id,
+                step: 0,
+            };
+            let metadata: TokenMetadata = self.burn(token_id.clone()).await?;
+            self.record_compensation(&m4, FlowKind::Burn, Compensation::RestoreToken { token: Token { id: token_id.clone(), metadata: metadata.clone() } }).await?;
+            let __message = Message::Burn_Minted {
+                m4: m4.next(),
+                metadata: metadata.clone(),
             };
-            let __message = Message::Buy_GetOwner { m4: m4.clone() };
             return Ok(ExecutionResult::default()
                 .with_authenticated_message(token_id.minted_chain, __message));
         }
         Operation::Receive { token_id } => {
+            let flow_id = self.start_flow(FlowKind::Receive, "Receive_Company").await?;
             let m4 = M4 {
                 original_chain: system_api::current_chain_id(),
                 original_operation: Some(operation),
                 original_ope_context: Some(context.clone()),
                 original_message: None,
                 original_msg_context: None,
+                flow_id,
+                step: 0,
             };
             let metadata: TokenMetadata = self.get_token(token_id).await?;
             if let Ok(stream_id) = serde_json::from_str::<StreamId>(&metadata.image) {
                 let __message = Message::Receive_Company {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     metadata: metadata.clone(),
                     stream_id: stream_id.clone(),
                 };
@@ -155,9 +326,9 @@ impl Contract for Aqueduct {
                     .with_authenticated_message(stream_id.company.clone(), __message));
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "Err(Error :: JsonError)".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::Json),
                 };
                 return Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message));
@@ -174,40 +345,189 @@ impl Contract for Aqueduct {
         context: &MessageContext,
         message: Message,
     ) -> Result<ExecutionResult<Self::Message>, Self::Error> {
+        self.receive_message(context, message).await
+    }
+
+    async fn handle_application_call(
+        &mut self,
+        _context: &CalleeContext,
+        _argument: (),
+        _forwarded_sessions: Vec<SessionId>,
+    ) -> Result<ApplicationCallResult<Self::Message, Self::Response, Self::SessionState>, Self::Error> {
+        Ok(ApplicationCallResult::default())
+    }
+
+    async fn handle_session_call(
+        &mut self,
+        _context: &CalleeContext,
+        _session: (),
+        _argument: (),
+        _forwarded_sessions: Vec<SessionId>,
+    ) -> Result<SessionCallResult<Self::Message, Self::Response, Self::SessionState>, Self::Error> {
+        Ok(SessionCallResult::default())
+    }
+}
+
+impl Aqueduct {
+    /// `execute_message` without the logging around it.
+    async fn receive_message(
+        &mut self,
+        context: &MessageContext,
+        message: Message,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        self.migrate().await?;
+        if let Some((m4, step)) = message.flow() {
+            if !self.check_step(m4).await? {
+                return Ok(ExecutionResult::default());
+            }
+            self.step_flow(m4, step).await?;
+        }
+        let result = self.handle_message(context, message).await?;
+        self.record_sent(&result).await?;
+        Ok(result)
+    }
+
+    /// Everything `execute_message` does once the step passed `check_step`.
+    async fn handle_message(
+        &mut self,
+        context: &MessageContext,
+        message: Message,
+    ) -> Result<ExecutionResult<Message>, Error> {
         match message.clone() {
             Message::Default => Ok(ExecutionResult::default()),
+            Message::Royalty {
+                token_id,
+                amount,
+            } => {
+                info!("royalty of {} on {:?}", amount, token_id);
+                self.credit(amount).await?;
+                Ok(ExecutionResult::default())
+            },
+            Message::MirrorListing {
+                token_id,
+                price,
+            } => {
+                self.mirror_listing(token_id, context.message_id.chain_id, price).await?;
+                Ok(ExecutionResult::default())
+            },
+            Message::PlaceOffer {
+                token_id,
+                amount,
+                expires,
+            } => {
+                let offer = Offer { bidder: context.message_id.chain_id, amount, expires };
+                self.place_offer(token_id, offer).await
+            },
+            Message::WithdrawOffer {
+                token_id,
+                amount,
+            } => self.withdraw_offer(token_id, amount, context.message_id.chain_id).await,
+            Message::AcceptOffer {
+                token_id,
+                amount,
+                bidder,
+            } => self.accept_offer(token_id, context.message_id.chain_id, amount, bidder).await,
+            Message::PlaceBid {
+                token_id,
+                amount,
+            } => {
+                let bid = Bid { bidder: context.message_id.chain_id, amount };
+                self.place_bid(token_id, bid).await
+            },
+            Message::CloseAuction {
+                token_id,
+            } => {
+                match self.close_auction(token_id).await {
+                    Ok(result) => Ok(result),
+                    Err(error) => {
+                        info!("auction not closed: {:?}", error);
+                        Ok(ExecutionResult::default())
+                    }
+                }
+            },
+            Message::SettleEscrow {
+                token_id,
+                seller,
+                amount,
+            } => self.settle_escrow(token_id, seller, amount).await,
+            Message::EscrowPayment {
+                token_id,
+                buyer,
+                amount,
+            } => self.receive_escrow_payment(token_id, buyer, amount).await,
+            Message::RefundEscrow {
+                token_id,
+                amount,
+            } => {
+                self.drop_settlement(&token_id, context.message_id.chain_id, amount).await?;
+                self.refund_escrow(token_id, amount).await?;
+                Ok(ExecutionResult::default())
+            },
+            Message::RecoverToken {
+                flow_id,
+                token,
+            } => {
+                self.recover_token(flow_id, token, context.message_id.chain_id).await
+            },
+            Message::TokenRecovered {
+                token,
+            } => {
+                if context.message_id.chain_id != token.id.minted_chain {
+                    info!("{} did not mint {:?}, not recovered", context.message_id.chain_id, token.id);
+                    return Ok(ExecutionResult::default());
+                }
+                self.transfer_to_me(&token).await?;
+                Ok(ExecutionResult::default())
+            },
+            Message::ReturnToken {
+                flow_id,
+                token,
+            } => {
+                self.return_token(flow_id, token, context.message_id.chain_id).await
+            },
+            Message::RecoverReturnedToken {
+                flow_id,
+                token,
+                owner,
+                returned,
+            } => {
+                self.recover_returned_token(flow_id, token, owner, context.message_id.chain_id, returned).await
+            },
 //m4 execute_message start
         Message::Result { m4, origin, result } => {
             info!(
                 "m4: {:?}
-origin: {}res: {}",
+origin: {}res: {:?}",
                 m4, origin, result
             );
-            Ok(ExecutionResult::default())
+            self.finish_flow(&m4, result.clone()).await?;
+            self.end_settlement(&m4, &result).await
         }
 
         Message::Buy_BackToPay {
             m4,
             cur_owner,
             price,
+            royalty_bps,
         } => {
             if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
                 if let Some(ope_context) = m4.original_ope_context.clone() {
-                    match self.debit(price).await {
+                    match self.escrow_payment(&m4, &token_id, price).await {
                         Ok(_) => {
-                            let __message = Message::Buy_ReceivePayment {
-                                m4: m4.clone(),
+                            let __message = Message::Buy_TransferFrom {
+                                m4: m4.next(),
                                 cur_owner: cur_owner.clone(),
                                 price: price.clone(),
+                                royalty_bps: royalty_bps.clone(),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(cur_owner, __message));
                         }
                         Err(error) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -215,18 +535,18 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedOperationContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedOperationError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
@@ -235,20 +555,22 @@ origin: {}res: {}",
         Message::Buy_GetOwner { m4 } => {
             if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
                 if let Some(ope_context) = m4.original_ope_context.clone() {
-                    match self.get_owner(token_id).await {
+                    match self.get_owner(token_id.clone()).await {
                         Ok(cur_owner) => {
+                            let royalty_bps: u16 = self.royalties.get(&token_id).await?.unwrap_or(0);
                             let __message = Message::Buy_GetPrice {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 cur_owner: cur_owner.clone(),
+                                royalty_bps: royalty_bps.clone(),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(cur_owner, __message));
                         }
                         Err(error) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -256,41 +578,46 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedOperationContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedOperationError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::Buy_GetPrice { m4, cur_owner } => {
+        Message::Buy_GetPrice {
+            m4,
+            cur_owner,
+            royalty_bps,
+        } => {
             if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
                 if let Some(ope_context) = m4.original_ope_context.clone() {
                     match self.get_price(token_id).await {
                         Ok(price) => {
                             let __message = Message::Buy_BackToPay {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 cur_owner: cur_owner.clone(),
                                 price: price.clone(),
+                                royalty_bps: royalty_bps.clone(),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
                         }
                         Err(error) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -298,59 +625,48 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedOperationContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedOperationError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::Buy_ReceivePayment {
+        Message::Buy_TransferFrom {
             m4,
             cur_owner,
             price,
+            royalty_bps,
         } => {
             if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
                 if let Some(ope_context) = m4.original_ope_context.clone() {
-                    match self.credit(price).await {
-                        Ok(_) => {
-                            if let Ok(data) = to_bytes(&price) {
-                                let message: Message = Message::SafeTransferFrom {
-                                    from: cur_owner,
-                                    to: m4.original_chain,
-                                    token_id,
-                                    data,
-                                };
-                                let __message = Message :: Result { m4 : m4 . clone () , origin : system_api :: current_chain_id () , result : "Ok(ExecutionResult :: default () . with_authenticated_message (cur_owner , message))" . to_string () , } ;
-                                return Ok(ExecutionResult::default()
-                                    .with_authenticated_message(m4.original_chain, __message));
-                            } else {
-                                self.debit(price).await;
-                                let __message = Message::Buy_ReceiveRollbackPayment {
-                                    m4: m4.clone(),
-                                    cur_owner: cur_owner.clone(),
-                                    price: price.clone(),
-                                };
-                                return Ok(ExecutionResult::default()
-                                    .with_authenticated_message(cur_owner, __message));
-                            }
-                            Ok(ExecutionResult::default())
+                    match self.sell(&m4, token_id.clone(), price).await {
+                        Ok(token) => {
+                            let __message = Message::Buy_UpdateMintedChain {
+                                m4: m4.next(),
+                                cur_owner: cur_owner.clone(),
+                                price: price.clone(),
+                                royalty_bps: royalty_bps.clone(),
+                                token: token.clone(),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(token_id.minted_chain, __message));
                         }
                         Err(error) => {
-                            let __message = Message::Result {
-                                m4: m4.clone(),
-                                origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                            let __message = Message::Buy_RefundEscrow {
+                                m4: m4.next(),
+                                price: price.clone(),
+                                error: ErrorCode::from(&error),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -358,182 +674,157 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedOperationContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedOperationError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::Buy_ReceiveRollbackPayment {
+        Message::Buy_UpdateMintedChain {
             m4,
             cur_owner,
             price,
+            royalty_bps,
+            token,
         } => {
             if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
                 if let Some(ope_context) = m4.original_ope_context.clone() {
-                    match self.credit(price).await {
+                    match self.transfer_update_minted(token_id.clone(), m4.original_chain, cur_owner).await {
                         Ok(_) => {
-                            let __message = Message::Result {
-                                m4: m4.clone(),
-                                origin: system_api::current_chain_id(),
-                                result: "Err(Error :: BcsPriceError)".to_string(),
+                            let __message = Message::Buy_TransferTo {
+                                m4: m4.next(),
+                                cur_owner: cur_owner.clone(),
+                                price: price.clone(),
+                                royalty_bps: royalty_bps.clone(),
+                                token: token.clone(),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
                         }
                         Err(error) => {
-                            let __message = Message::Result {
-                                m4: m4.clone(),
-                                origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                            let __message = Message::Buy_RollbackOwner {
+                                m4: m4.next(),
+                                price: price.clone(),
+                                token: token.clone(),
+                                error: ErrorCode::from(&error),
                             };
                             return Ok(ExecutionResult::default()
-                                .with_authenticated_message(m4.original_chain, __message));
+                                .with_authenticated_message(cur_owner, __message));
                         }
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedOperationContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedOperationError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::Receive_Company {
+        Message::Buy_TransferTo {
             m4,
-            metadata,
-            stream_id,
+            cur_owner,
+            price,
+            royalty_bps,
+            token,
         } => {
-            if let Some(Operation::Receive { token_id }) = m4.original_operation.clone() {
+            if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
                 if let Some(ope_context) = m4.original_ope_context.clone() {
-                    match self.handle_receive(stream_id, m4.original_chain).await {
+                    match self.receive_bought(&m4, &token, cur_owner, price).await {
                         Ok(_) => {
-                            let __message = Message::Result {
-                                m4: m4.clone(),
-                                origin: system_api::current_chain_id(),
-                                result: "Ok(ExecutionResult :: default ())".to_string(),
+                            let __message = Message::Buy_ReceivePayment {
+                                m4: m4.next(),
+                                cur_owner: cur_owner.clone(),
+                                price: price.clone(),
+                                royalty_bps: royalty_bps.clone(),
                             };
                             return Ok(ExecutionResult::default()
-                                .with_authenticated_message(m4.original_chain, __message));
+                                .with_authenticated_message(cur_owner, __message));
                         }
                         Err(error) => {
-                            let __message = Message::Result {
-                                m4: m4.clone(),
-                                origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                            let __message = Message::Buy_RollbackMinted {
+                                m4: m4.next(),
+                                cur_owner: cur_owner.clone(),
+                                price: price.clone(),
+                                token: token.clone(),
+                                error: ErrorCode::from(&error),
                             };
                             return Ok(ExecutionResult::default()
-                                .with_authenticated_message(m4.original_chain, __message));
+                                .with_authenticated_message(token_id.minted_chain, __message));
                         }
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedOperationContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedOperationError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::SafeTransferFrom {
-            from,
-            to,
-            token_id,
-            data,
+        Message::Buy_ReceivePayment {
+            m4,
+            cur_owner,
+            price,
+            royalty_bps,
         } => {
-            let m4 = M4 {
-                original_chain: system_api::current_chain_id(),
-                original_operation: None,
-                original_ope_context: None,
-                original_message: Some(ActualMessage::SafeTransferFrom {
-                    from,
-                    to,
-                    token_id,
-                    data,
-                }),
-                original_msg_context: Some(context.clone()),
-            };
-            let sender: ChainId = system_api::current_chain_id();
-            if from != sender {
-                let __message = Message::Result {
-                    m4: m4.clone(),
-                    origin: system_api::current_chain_id(),
-                    result: "Err(Error :: NotAnOperatorNorApproved)".to_string(),
-                };
-                return Ok(ExecutionResult::default()
-                    .with_authenticated_message(m4.original_chain, __message));
-            }
-            let __message = Message::SafeTransferFrom_CheckOwn {
-                m4: m4.clone(),
-                sender: sender.clone(),
-            };
-            return Ok(ExecutionResult::default().with_authenticated_message(from, __message));
-        }
-        Message::SafeTransferFrom_CheckOwn { m4, sender } => {
-            if let Some(ActualMessage::SafeTransferFrom {
-                from,
-                to,
-                token_id,
-                data,
-            }) = m4.original_message.clone()
-            {
-                if let Some(msg_context) = m4.original_msg_context.clone() {
-                    match self.check_own(token_id).await {
-                        Ok(own) => {
-                            if !own {
-                                let __message = Message::Result {
-                                    m4: m4.clone(),
-                                    origin: system_api::current_chain_id(),
-                                    result: "Err(Error :: DoesNotOwnToken)".to_string(),
-                                };
-                                return Ok(ExecutionResult::default()
-                                    .with_authenticated_message(m4.original_chain, __message));
-                            }
-                            let __message = Message::SafeTransferFrom_TransferFrom {
-                                m4: m4.clone(),
-                                sender: sender.clone(),
-                                own: own.clone(),
+            if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.credit(price).await {
+                        Ok(_) => {
+                            //royalty to the minting chain, reported in the result, and the sale ends
+                            //for the seller too (implemented by hand after generating m4)
+                            let royalty: Amount = self.pay_royalty(&token_id, price, royalty_bps).await;
+                            let outcome = FlowOutcome::Ok(FlowPayload::Bought { price, royalty });
+                            self.finish_flow(&m4, outcome.clone()).await?;
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: outcome,
                             };
-                            return Ok(ExecutionResult::default()
-                                .with_authenticated_message(from, __message));
+                            let mut result = ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message);
+                            if royalty > Amount::zero() {
+                                result = result.with_authenticated_message(token_id.minted_chain, Message::Royalty { token_id, amount: royalty });
+                            }
+                            return Ok(result);
                         }
                         Err(error) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -541,88 +832,48 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedMessageContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedMessageError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::SafeTransferFrom_OnERC721Received {
+        Message::Buy_ReceiveRollbackPayment {
             m4,
-            sender,
-            own,
-            token,
+            cur_owner,
+            price,
+            royalty_bps,
         } => {
-            if let Some(ActualMessage::SafeTransferFrom {
-                from,
-                to,
-                token_id,
-                data,
-            }) = m4.original_message.clone()
-            {
-                if let Some(msg_context) = m4.original_msg_context.clone() {
-                    match self
-                        .on_erc721_received(OnERC721Received {
-                            from: from.clone(),
-                            to: to.clone(),
-                            token_id: token_id.clone(),
-                            data: data.clone(),
-                        })
-                        .await
-                    {
-                        Ok(check) => {
-                            if let Ok(b) = to_bytes(&OnERC721Received {
-                                from: from.clone(),
-                                to: to.clone(),
-                                token_id: token_id.clone(),
-                                data: data.clone(),
-                            }) {
-                                let mut bb = b;
-                                //keccak256(&mut bb);
-                                if check != bb {
-                                    let __message = Message::Result {
-                                        m4: m4.clone(),
-                                        origin: system_api::current_chain_id(),
-                                        result: "Err(Error :: AfterTransferCheckFailed)"
-                                            .to_string(),
-                                    };
-                                    return Ok(ExecutionResult::default()
-                                        .with_authenticated_message(m4.original_chain, __message));
-                                }
-                                let __message = Message::Result {
-                                    m4: m4.clone(),
-                                    origin: system_api::current_chain_id(),
-                                    result: "Ok(ExecutionResult :: default ())".to_string(),
-                                };
-                                return Ok(ExecutionResult::default()
-                                    .with_authenticated_message(m4.original_chain, __message));
-                            } else {
-                                let __message = Message::Result {
-                                    m4: m4.clone(),
-                                    origin: system_api::current_chain_id(),
-                                    result: "Err(Error :: BcsError)".to_string(),
-                                };
-                                return Ok(ExecutionResult::default()
-                                    .with_authenticated_message(m4.original_chain, __message));
-                            }
-                            Ok(ExecutionResult::default())
+            //only sent by the Buy flow before the payment was escrowed: the seller could not
+            //encode the price and sent it back (implemented by hand after generating m4)
+            if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.credit(price).await {
+                        Ok(_) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Err(ErrorCode::Bcs),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
                         }
                         Err(error) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -630,48 +881,48 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedMessageContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedMessageError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::SafeTransferFrom_TransferFrom { m4, sender, own } => {
-            if let Some(ActualMessage::SafeTransferFrom {
-                from,
-                to,
-                token_id,
-                data,
-            }) = m4.original_message.clone()
-            {
-                if let Some(msg_context) = m4.original_msg_context.clone() {
-                    match self.transfer_from_me(token_id).await {
-                        Ok(token) => {
-                            let __message = Message::SafeTransferFrom_TransferTo {
-                                m4: m4.clone(),
-                                sender: sender.clone(),
-                                own: own.clone(),
+        Message::Buy_RollbackMinted {
+            m4,
+            cur_owner,
+            price,
+            token,
+            error,
+        } => {
+            if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.transfer_update_minted(token_id.clone(), cur_owner, m4.original_chain).await {
+                        Ok(_) => {
+                            let __message = Message::Buy_RollbackOwner {
+                                m4: m4.next(),
+                                price: price.clone(),
                                 token: token.clone(),
+                                error: error.clone(),
                             };
                             return Ok(ExecutionResult::default()
-                                .with_authenticated_message(to, __message));
+                                .with_authenticated_message(cur_owner, __message));
                         }
-                        Err(error) => {
+                        Err(error2) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::RollbackFailed(error, ErrorCode::from(&error2)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -679,53 +930,51 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedMessageContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedMessageError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::SafeTransferFrom_TransferTo {
+        Message::Buy_RollbackOwner {
             m4,
-            sender,
-            own,
+            price,
             token,
+            error,
         } => {
-            if let Some(ActualMessage::SafeTransferFrom {
-                from,
-                to,
-                token_id,
-                data,
-            }) = m4.original_message.clone()
-            {
-                if let Some(msg_context) = m4.original_msg_context.clone() {
-                    match self.transfer_to_me(&token).await {
+            if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.unsell(&token, price).await {
                         Ok(_) => {
-                            let __message = Message::SafeTransferFrom_UpdateMintedChain {
-                                m4: m4.clone(),
-                                sender: sender.clone(),
-                                own: own.clone(),
-                                token: token.clone(),
+                            //the token is listed again, on the minting chain too, and the sale ends for
+                            //the seller (implemented by hand after generating m4)
+                            self.finish_flow(&m4, FlowOutcome::Err(error.clone())).await?;
+                            let __message = Message::Buy_RefundEscrow {
+                                m4: m4.next(),
+                                price: price.clone(),
+                                error: error.clone(),
                             };
+                            let mirror = Message::MirrorListing { token_id: token_id.clone(), price: Some(price) };
                             return Ok(ExecutionResult::default()
-                                .with_authenticated_message(token_id.minted_chain, __message));
+                                .with_authenticated_message(token_id.minted_chain, mirror)
+                                .with_authenticated_message(m4.original_chain, __message));
                         }
-                        Err(error) => {
+                        Err(error2) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::RollbackFailed(error, ErrorCode::from(&error2)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -733,53 +982,45 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedMessageContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedMessageError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::SafeTransferFrom_UpdateMintedChain {
+        Message::Buy_RefundEscrow {
             m4,
-            sender,
-            own,
-            token,
+            price,
+            error,
         } => {
-            if let Some(ActualMessage::SafeTransferFrom {
-                from,
-                to,
-                token_id,
-                data,
-            }) = m4.original_message.clone()
-            {
-                if let Some(msg_context) = m4.original_msg_context.clone() {
-                    match self.transfer_update_minted(token_id, to).await {
+            if let Some(Operation::Buy { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.refund_payment(&m4, token_id.clone(), price).await {
                         Ok(_) => {
-                            let __message = Message::SafeTransferFrom_OnERC721Received {
-                                m4: m4.clone(),
-                                sender: sender.clone(),
-                                own: own.clone(),
-                                token: token.clone(),
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Err(error),
                             };
                             return Ok(ExecutionResult::default()
-                                .with_authenticated_message(to, __message));
+                                .with_authenticated_message(m4.original_chain, __message));
                         }
-                        Err(error) => {
+                        Err(error2) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::RollbackFailed(error, ErrorCode::from(&error2)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -787,95 +1028,46 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedMessageContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedMessageError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::Result { m4, origin, result } => {
-            let m4 = M4 {
-                original_chain: system_api::current_chain_id(),
-                original_operation: None,
-                original_ope_context: None,
-                original_message: Some(ActualMessage::Result { /*m4,*/ origin: origin.clone(), result: result.clone() }),
-                original_msg_context: Some(context.clone()),
-            };
-            info!("m4: {:?} origin: {} res: {}", m4, origin, result);
-            if let Some(ActualMessage::SafeTransferFrom {
-                from,
-                to,
-                token_id,
-                data,
-            }) = m4.original_message.clone()
-            {
-                if result.contains("Err") {
-                    if let Ok(price) = from_bytes::<Amount>(&data) {
-                        let __message = Message::Result_BackToPay {
-                            m4: m4.clone(),
-                            from: from.clone(),
-                            to: to.clone(),
-                            price: price.clone(),
-                        };
-                        return Ok(
-                            ExecutionResult::default().with_authenticated_message(from, __message)
-                        );
-                    } else {
-                        let __message = Message::Result {
-                            m4: m4.clone(),
-                            origin: system_api::current_chain_id(),
-                            result: "Err(Error :: BcsError)".to_string(),
-                        };
-                        return Ok(ExecutionResult::default()
-                            .with_authenticated_message(m4.original_chain, __message));
-                    }
-                }
-            }
-            let __message = Message::Result {
-                m4: m4.clone(),
-                origin: system_api::current_chain_id(),
-                result: "Ok(ExecutionResult :: default ())".to_string(),
-            };
-            return Ok(
-                ExecutionResult::default().with_authenticated_message(m4.original_chain, __message)
-            );
-        }
-        Message::Result_BackToPay {
+        Message::Receive_Company {
             m4,
-            from,
-            to,
-            price,
+            metadata,
+            stream_id,
         } => {
-            if let Some(ActualMessage::Result { /*m4,*/ origin, result }) = m4.original_message.clone()
-            {
-                if let Some(msg_context) = m4.original_msg_context.clone() {
-                    match self.debit(price).await {
-                        Ok(_) => {
-                            let __message = Message::Result_ReceivePayment {
-                                m4: m4.clone(),
-                                from: from.clone(),
-                                to: to.clone(),
-                                price: price.clone(),
+            if let Some(Operation::Receive { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.pay_out(stream_id.clone(), m4.original_chain).await {
+                        Ok(payout) => {
+                            let __message = Message::Receive_Credit {
+                                m4: m4.next(),
+                                metadata: metadata.clone(),
+                                stream_id: stream_id.clone(),
+                                payout: payout.clone(),
                             };
                             return Ok(ExecutionResult::default()
-                                .with_authenticated_message(to, __message));
+                                .with_authenticated_message(m4.original_chain, __message));
                         }
                         Err(error) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -883,54 +1075,83 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedMessageContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedMessageError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-        Message::Result_ReceivePayment {
-            m4,
-            from,
-            to,
-            price,
-        } => {
-            if let Some(ActualMessage::Result { /*m4,*/ origin, result }) = m4.original_message.clone()
-            {
-                if let Some(msg_context) = m4.original_msg_context.clone() {
-                    match self.credit(price).await {
+        Message::Burn_Minted { m4, metadata } => {
+            if let Some(Operation::Burn { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.clear_burned(token_id, &metadata, m4.original_chain).await {
                         Ok(_) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: "Ok(ExecutionResult :: default ())".to_string(),
+                                result: FlowOutcome::Ok(FlowPayload::Done),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                        Err(error) => {
+                            let __message = Message::Burn_RollbackOwner {
+                                m4: m4.next(),
+                                metadata: metadata.clone(),
+                                error: ErrorCode::from(&error),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::Burn_RollbackOwner { m4, metadata, error } => {
+            if let Some(Operation::Burn { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.transfer_to_me(&Token { id: token_id, metadata }).await {
+                        Ok(_) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: "Ok(ExecutionResult :: default ())".to_string(),
+                                result: FlowOutcome::Err(error),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
                         }
-                        Err(error) => {
+                        Err(error2) => {
                             let __message = Message::Result {
-                                m4: m4.clone(),
+                                m4: m4.next(),
                                 origin: system_api::current_chain_id(),
-                                result: format! { "{:?}" , error },
+                                result: FlowOutcome::RollbackFailed(error, ErrorCode::from(&error2)),
                             };
                             return Ok(ExecutionResult::default()
                                 .with_authenticated_message(m4.original_chain, __message));
@@ -938,139 +1159,756 @@ origin: {}res: {}",
                     }
                 } else {
                     let __message = Message::Result {
-                        m4: m4.clone(),
+                        m4: m4.next(),
                         origin: system_api::current_chain_id(),
-                        result: "M4MismatchedMessageContextError".to_string(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
                     };
                     Ok(ExecutionResult::default()
                         .with_authenticated_message(m4.original_chain, __message))
                 }
             } else {
                 let __message = Message::Result {
-                    m4: m4.clone(),
+                    m4: m4.next(),
                     origin: system_api::current_chain_id(),
-                    result: "M4MismatchedMessageError".to_string(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
                 };
                 Ok(ExecutionResult::default()
                     .with_authenticated_message(m4.original_chain, __message))
             }
         }
-//m4 execute_message end
-//rmb to change: original_message: Some(ActualMessage::Result { m4: serde_json::to_string(&m4) , origin, result }),
-        }
-    }
-
-    async fn handle_application_call(
-        &mut self,
-        _context: &CalleeContext,
-        _argument: (),
-        _forwarded_sessions: Vec<SessionId>,
-    ) -> Result<ApplicationCallResult<Self::Message, Self::Response, Self::SessionState>, Self::Error> {
-        Ok(ApplicationCallResult::default())
-    }
-
-    async fn handle_session_call(
-        &mut self,
-        _context: &CalleeContext,
-        _session: (),
-        _argument: (),
-        _forwarded_sessions: Vec<SessionId>,
-    ) -> Result<SessionCallResult<Self::Message, Self::Response, Self::SessionState>, Self::Error> {
-        Ok(SessionCallResult::default())
-    }
-}
-
-impl Aqueduct {
-    async fn get_owner(&mut self, token: TokenId) -> Result<ChainId, Error> {
-        if let Some(owner) = self.current_owner_minted.get(&token).await? {
-            Ok(owner)
+        Message::Receive_Credit {
+            m4,
+            metadata,
+            stream_id,
+            payout,
+        } => {
+            if let Some(Operation::Receive { token_id }) = m4.original_operation.clone() {
+                if let Some(ope_context) = m4.original_ope_context.clone() {
+                    match self.receive_payout(token_id, payout).await {
+                        Ok(payload) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Ok(payload),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                        Err(error) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedOperationContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedOperation),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom {
+            from,
+            to,
+            token_id,
+            data,
+        } => {
+            let flow_id = self.start_flow(FlowKind::SafeTransferFrom, "SafeTransferFrom_CheckOwn").await?;
+            let m4 = M4 {
+                original_chain: system_api::current_chain_id(),
+                original_operation: None,
+                original_ope_context: None,
+                original_message: Some(ActualMessage::SafeTransferFrom {
+                    from,
+                    to,
+                    token_id,
+                    data,
+                }),
+                original_msg_context: Some(context.clone()),
+                flow_id,
+                step: 0,
+            };
+            let sender: ChainId = system_api::current_chain_id();
+            let __message = Message::SafeTransferFrom_CheckOwn {
+                m4: m4.next(),
+                sender: sender.clone(),
+            };
+            return Ok(ExecutionResult::default().with_authenticated_message(from, __message));
+        }
+        Message::SafeTransferFrom_CheckOwn { m4, sender } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self.check_own_or_approved(token_id, sender).await {
+                        Ok(own) => {
+                            if !own {
+                                let __message = Message::Result {
+                                    m4: m4.next(),
+                                    origin: system_api::current_chain_id(),
+                                    result: FlowOutcome::Err(ErrorCode::NotOwner),
+                                };
+                                return Ok(ExecutionResult::default()
+                                    .with_authenticated_message(m4.original_chain, __message));
+                            }
+                            let __message = Message::SafeTransferFrom_TransferFrom {
+                                m4: m4.next(),
+                                sender: sender.clone(),
+                                own: own.clone(),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(from, __message));
+                        }
+                        Err(error) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom_OnERC721Received {
+            m4,
+            sender,
+            own,
+            token,
+        } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self
+                        .accept_transfer(OnERC721Received {
+                            from: from.clone(),
+                            to: to.clone(),
+                            token_id: token_id.clone(),
+                            data: data.clone(),
+                        })
+                        .await
+                    {
+                        Ok(payload) => {
+                            //by hand after generating m4: the token stays here for good, so recovery on
+                            //the origin chain cannot take it back any more
+                            if m4.original_chain != system_api::current_chain_id() {
+                                self.last_steps.insert(&m4.flow_id, FENCED_STEP)?;
+                            }
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Ok(payload),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                        Err(error) => {
+                            //refused: the token leaves `to` again, then the minting chain and `from` are rolled back
+                            match self.transfer_from_me(token_id.clone()).await {
+                                Ok(token) => {
+                                    let __message = Message::SafeTransferFrom_RollbackMinted {
+                                        m4: m4.next(),
+                                        token: token.clone(),
+                                        error: ErrorCode::from(&error),
+                                    };
+                                    return Ok(ExecutionResult::default()
+                                        .with_authenticated_message(token_id.minted_chain, __message));
+                                }
+                                Err(error2) => {
+                                    let __message = Message::Result {
+                                        m4: m4.next(),
+                                        origin: system_api::current_chain_id(),
+                                        result: FlowOutcome::RollbackFailed(ErrorCode::from(&error), ErrorCode::from(&error2)),
+                                    };
+                                    return Ok(ExecutionResult::default()
+                                        .with_authenticated_message(m4.original_chain, __message));
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom_RollbackMinted { m4, token, error } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self.transfer_update_minted(token_id.clone(), from, to).await {
+                        Ok(_) => {
+                            return Ok(Self::rollback_safe_transfer(&m4, from, token, error));
+                        }
+                        Err(error2) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::RollbackFailed(error, ErrorCode::from(&error2)),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom_RollbackTo { m4, token, error } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self.transfer_from_me(token_id.clone()).await {
+                        Ok(_) => {
+                            return Ok(Self::rollback_safe_transfer(&m4, from, token, error));
+                        }
+                        Err(error2) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::RollbackFailed(error, ErrorCode::from(&error2)),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom_RollbackOwner { m4, token, error } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self.transfer_to_me(&token).await {
+                        Ok(_) => {
+                            //by hand after generating m4: the token is back, nothing left to recover here
+                            self.finish_flow(&m4, FlowOutcome::Err(error.clone())).await?;
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Err(error),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                        Err(error2) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::RollbackFailed(error, ErrorCode::from(&error2)),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom_TransferFrom { m4, sender, own } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self.transfer_from_me(token_id).await {
+                        Ok(token) => {
+                            //by hand after generating m4: `from` keeps the flow recoverable, whoever started it
+                            self.record_compensation(&m4, FlowKind::SafeTransferFrom, Compensation::RestoreSentToken { token: token.clone(), recipient: to }).await?;
+                            let __message = Message::SafeTransferFrom_TransferTo {
+                                m4: m4.next(),
+                                sender: sender.clone(),
+                                own: own.clone(),
+                                token: token.clone(),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(to, __message));
+                        }
+                        Err(error) => {
+                            let __message = Message::Result {
+                                m4: m4.next(),
+                                origin: system_api::current_chain_id(),
+                                result: FlowOutcome::Err(ErrorCode::from(&error)),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(m4.original_chain, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom_TransferTo {
+            m4,
+            sender,
+            own,
+            token,
+        } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self.transfer_to_me(&token).await {
+                        Ok(_) => {
+                            let __message = Message::SafeTransferFrom_UpdateMintedChain {
+                                m4: m4.next(),
+                                sender: sender.clone(),
+                                own: own.clone(),
+                                token: token.clone(),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(token_id.minted_chain, __message));
+                        }
+                        Err(error) => {
+                            //rollback by hand after generating m4
+                            return Ok(Self::rollback_safe_transfer(&m4, from, token, ErrorCode::from(&error)));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+        Message::SafeTransferFrom_UpdateMintedChain {
+            m4,
+            sender,
+            own,
+            token,
+        } => {
+            if let Some(ActualMessage::SafeTransferFrom {
+                from,
+                to,
+                token_id,
+                data,
+            }) = m4.original_message.clone()
+            {
+                if let Some(msg_context) = m4.original_msg_context.clone() {
+                    match self.transfer_update_minted(token_id, to, from).await {
+                        Ok(_) => {
+                            let __message = Message::SafeTransferFrom_OnERC721Received {
+                                m4: m4.next(),
+                                sender: sender.clone(),
+                                own: own.clone(),
+                                token: token.clone(),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(to, __message));
+                        }
+                        Err(error) => {
+                            //rollback by hand after generating m4: the token leaves `to` again
+                            let __message = Message::SafeTransferFrom_RollbackTo {
+                                m4: m4.next(),
+                                token: token.clone(),
+                                error: ErrorCode::from(&error),
+                            };
+                            return Ok(ExecutionResult::default()
+                                .with_authenticated_message(to, __message));
+                        }
+                    }
+                } else {
+                    let __message = Message::Result {
+                        m4: m4.next(),
+                        origin: system_api::current_chain_id(),
+                        result: FlowOutcome::Err(ErrorCode::MismatchedMessageContext),
+                    };
+                    Ok(ExecutionResult::default()
+                        .with_authenticated_message(m4.original_chain, __message))
+                }
+            } else {
+                let __message = Message::Result {
+                    m4: m4.next(),
+                    origin: system_api::current_chain_id(),
+                    result: FlowOutcome::Err(ErrorCode::MismatchedMessage),
+                };
+                Ok(ExecutionResult::default()
+                    .with_authenticated_message(m4.original_chain, __message))
+            }
+        }
+//m4 execute_message end
+//rmb to change: original_message: Some(ActualMessage::Result { m4: serde_json::to_string(&m4) , origin, result }),
+        }
+    }
+
+    /// Numbers a flow starting on this chain and records it as pending at its first `step`.
+    async fn start_flow(&mut self, kind: FlowKind, step: &str) -> Result<FlowId, Error> {
+        let index = *self.flow_count.get();
+        self.flow_count.set(index + 1);
+        let id = FlowId { chain_id: system_api::current_chain_id(), index };
+        let flow = FlowState::new(id.clone(), kind, step, system_api::current_system_time());
+        self.flows.insert(&id, flow)?;
+        self.pending_flow_ids.insert(&id)?;
+        Ok(id)
+    }
+    /// Records `step` of a flow this chain started or gave a token up in, other chains keep no
+    /// flow state.
+    async fn step_flow(&mut self, m4: &M4, step: &str) -> Result<(), Error> {
+        if let Some(mut flow) = self.flows.get(&m4.flow_id).await? {
+            flow.step = step.to_string();
+            flow.updated = system_api::current_system_time();
+            self.flows.insert(&m4.flow_id, flow)?;
+        }
+        Ok(())
+    }
+    /// Records the steps this chain sends of flows it keeps an entry of, so a pending flow shows
+    /// the step it is waiting on rather than the last one it came back with.
+    async fn record_sent(&mut self, result: &ExecutionResult<Message>) -> Result<(), Error> {
+        for (_, _, message) in &result.messages {
+            if let Some((m4, step)) = message.flow() {
+                self.step_flow(m4, step).await?;
+            }
+        }
+        Ok(())
+    }
+    /// Whether a step of a flow runs here: a step this chain handled already, one older than
+    /// the last step of the flow it handled, or any step of a flow recovered here is logged and
+    /// dropped before it can touch balances or ownership again. Failing instead would leave the
+    /// message at the head of the inbox, and no `Message::Result` reports the drop since the
+    /// origin chain would take it for how the flow ended.
+    async fn check_step(&mut self, m4: &M4) -> Result<bool, Error> {
+        match classify_step(self.last_steps.get(&m4.flow_id).await?, m4.step) {
+            StepCheck::Fresh => {
+                self.last_steps.insert(&m4.flow_id, m4.step)?;
+                Ok(true)
+            }
+            check => {
+                info!("step {} of flow {:?} dropped: {:?}", m4.step, m4.flow_id, check);
+                Ok(false)
+            }
+        }
+    }
+    /// Records how a flow that started on this chain ended, unless it was recovered already.
+    async fn finish_flow(&mut self, m4: &M4, outcome: FlowOutcome) -> Result<(), Error> {
+        if let Some(mut flow) = self.flows.get(&m4.flow_id).await? {
+            if flow.outcome.is_some() {
+                return Ok(());
+            }
+            flow.outcome = Some(outcome);
+            self.flows.insert(&m4.flow_id, flow)?;
+            self.pending_flow_ids.remove(&m4.flow_id)?;
+        }
+        Ok(())
+    }
+    async fn get_owner(&mut self, token: TokenId) -> Result<ChainId, Error> {
+        if let Some(owner) = self.current_owner_minted.get(&token).await? {
+            Ok(owner)
         } else {
             Err(Error::NoOwnerInMintedError)
         }
     }
     async fn get_token(&mut self, token_id: TokenId) -> Result<TokenMetadata, Error> {
-        let temptoken = Token {
-            id: TokenId {
-                minted_chain: system_api::current_chain_id(),
-                index: 0,
-            },
-            metadata: TokenMetadata {
-                name: "".to_string(),
-                description: "".to_string(),
-                image: "".to_string(),
-            },
-        };
-        let mut realtoken = temptoken.clone();
-        self.owned_tokens.for_each_index(|key| {
-            if key.id == token_id {
-                realtoken = key;
-            }
-            Ok(())
-        }).await?;
-        if realtoken == temptoken {
-            Err(Error::ThisChainDoesNotOwnThisTokenError)
+        self.owned_tokens
+            .get(&token_id)
+            .await?
+            .ok_or(Error::ThisChainDoesNotOwnThisTokenError)
+    }
+    async fn check_own(&mut self, token: TokenId) -> Result<bool, Error> {
+        Ok(self.owned_tokens.get(&token).await?.is_some())
+    }
+    /// Whether this chain owns `token` and lets `sender` move it: `sender` is this chain, the
+    /// approved spender of the token or an operator of this chain.
+    async fn check_own_or_approved(&mut self, token: TokenId, sender: ChainId) -> Result<bool, Error> {
+        if !self.check_own(token.clone()).await? {
+            return Ok(false);
+        }
+        if sender == system_api::current_chain_id()
+            || self.token_approvals.get(&token).await? == Some(sender)
+            || self.operators.contains(&sender).await?
+        {
+            Ok(true)
         } else {
-            Ok(realtoken.metadata)
+            Err(Error::NotAnOperatorNorApproved)
         }
     }
-    async fn check_own(&mut self, token: TokenId) -> Result<bool, Error> {
-        let mut b = false;
-        self.owned_tokens.for_each_index(|key| {
-            b = b || key.id == token;
-            Ok(())
-        }).await?;
-        Ok(b)
+    /// Recipient side check of a transfer, `AfterTransferCheckFailed` if the recipient refuses.
+    async fn check_erc721_received(&mut self, oer: OnERC721Received) -> Result<(), Error> {
+        if !self.on_erc721_received(oer).await.accepts() {
+            return Err(Error::AfterTransferCheckFailed);
+        }
+        Ok(())
     }
-    async fn on_erc721_received(&mut self, oer: OnERC721Received) -> Result<Vec<u8>, Error> {
-        let mut b = to_bytes(&oer)?;
-        //keccak256(&mut b);
-        Ok(b.to_vec())
+    /// Shared by every `SafeTransferFrom` leg failing once the token left `from`, after what
+    /// the later legs did was undone: the token goes back to `from` and the flow ends with `error`.
+    fn rollback_safe_transfer(m4: &M4, from: ChainId, token: Token, error: ErrorCode) -> ExecutionResult<Message> {
+        let message = Message::SafeTransferFrom_RollbackOwner { m4: m4.next(), token, error };
+        ExecutionResult::default().with_authenticated_message(from, message)
+    }
+    /// Recipient side of the last `SafeTransferFrom` step: the receiver has to accept the
+    /// token, which is then paid for if it settles an accepted offer or a won auction.
+    async fn accept_transfer(&mut self, oer: OnERC721Received) -> Result<FlowPayload, Error> {
+        self.check_erc721_received(oer.clone()).await?;
+        self.pay_settlement(&oer.token_id, oer.from, &oer.data).await
+    }
+    /// Answer of the application registered with `Operation::RegisterReceiver`. The call is
+    /// unauthenticated, the receiver is chosen by the recipient and never acts as the sender.
+    async fn on_erc721_received(&mut self, oer: OnERC721Received) -> ReceiverAnswer {
+        match *self.receiver.get() {
+            Some(receiver) => {
+                let receiver = receiver.with_abi::<Erc721ReceiverAbi>();
+                match self.call_application(false, receiver, &oer, vec![]).await {
+                    Ok((answer, _)) => ReceiverAnswer::Answered(answer),
+                    Err(error) => {
+                        info!("receiver refused {:?}: {:?}", oer.token_id, error);
+                        ReceiverAnswer::CallFailed
+                    }
+                }
+            }
+            None => ReceiverAnswer::Unregistered,
+        }
     }
+    /// Gives a token up, refused while its auction holds an escrowed bid.
     async fn transfer_from_me(&mut self, token: TokenId) -> Result<Token, Error> {
-        let mut realtoken = Token {
-            id: TokenId {
-                minted_chain: system_api::current_chain_id(),
-                index: 0,
-            },
-            metadata: TokenMetadata {
-                name: "".to_string(),
-                description: "".to_string(),
-                image: "".to_string(),
-            },
-        };
-        self.owned_tokens.for_each_index(|key| {
-            if key.id == token {
-                realtoken = key;
+        let metadata = self.get_token(token.clone()).await?;
+        self.ensure_no_bids(&token).await?;
+        self.owned_tokens.remove(&token)?;
+        self.listings.remove(&token)?;
+        self.token_approvals.remove(&token)?;
+        Ok(Token { id: token, metadata })
+    }
+    /// Holder side of `Operation::Burn`.
+    async fn burn(&mut self, token_id: TokenId) -> Result<TokenMetadata, Error> {
+        Ok(self.transfer_from_me(token_id).await?.metadata)
+    }
+    /// Minting chain side of `Operation::Burn`: forgets the owner and, the minting chain being
+    /// the company, lets `Operation::RetireStream` drop the stream behind the token.
+    async fn clear_burned(&mut self, token_id: TokenId, metadata: &TokenMetadata, burner: ChainId) -> Result<(), Error> {
+        if self.get_owner(token_id.clone()).await? != burner {
+            return Err(Error::ThisChainDoesNotOwnThisTokenError);
+        }
+        self.current_owner_minted.remove(&token_id)?;
+        self.market.remove(&token_id)?;
+        if let Ok(stream_id) = serde_json::from_str::<StreamId>(&metadata.image) {
+            if stream_id.company == system_api::current_chain_id() {
+                self.burned_streams.insert(&stream_id)?;
             }
-            Ok(())
-        }).await?;
-        self.owned_tokens.remove(&realtoken)?;
-        //self.token_approvals.remove(&token)?;
-        Ok(realtoken)
+        }
+        Ok(())
+    }
+    /// Drops a stream nobody holds anymore, together with whatever it still owed.
+    async fn retire_stream(&mut self, stream_id: StreamId) -> Result<(), Error> {
+        if self.nfts.get(&stream_id).await?.is_none() {
+            return Err(Error::CompanyDoesntHaveStreamError);
+        }
+        if !self.burned_streams.contains(&stream_id).await? {
+            return Err(Error::StreamStillHeldError);
+        }
+        self.nfts.remove(&stream_id)?;
+        self.arrears.remove(&stream_id)?;
+        self.burned_streams.remove(&stream_id)?;
+        Ok(())
     }
     async fn transfer_to_me(&mut self, token: &Token) -> Result<(), Error> {
-        self.owned_tokens.insert(token)?;
+        self.owned_tokens.insert(&token.id, token.metadata.clone())?;
         Ok(())
     }
-    async fn transfer_update_minted(&mut self, token: TokenId, new: ChainId) -> Result<(), Error> {
+    /// Minting chain side of `token` moving from `previous` to `new`, refused unless `previous`
+    /// is the owner on record.
+    async fn transfer_update_minted(&mut self, token: TokenId, new: ChainId, previous: ChainId) -> Result<(), Error> {
+        if self.current_owner_minted.get(&token).await? != Some(previous) {
+            return Err(Error::OwnerChangedError);
+        }
         self.current_owner_minted.insert(&token, new)?;
+        self.market.remove(&token)?;
+        Ok(())
+    }
+    /// Minting chain side of `Operation::List` and `Operation::Cancel`. Only the current owner
+    /// can list, a listing from a former owner is ignored.
+    async fn mirror_listing(&mut self, token_id: TokenId, seller: ChainId, price: Option<Amount>) -> Result<(), Error> {
+        match price {
+            Some(price) => match self.current_owner_minted.get(&token_id).await? {
+                Some(owner) if owner == seller => {
+                    self.market.insert(&token_id, MarketListing { seller, price })?;
+                }
+                owner => info!("{} listed {:?} owned by {:?}, not mirrored", seller, token_id, owner),
+            },
+            None => {
+                if let Some(listing) = self.market.get(&token_id).await? {
+                    if listing.seller == seller {
+                        self.market.remove(&token_id)?;
+                    }
+                }
+            }
+        }
         Ok(())
     }
     async fn get_price(&mut self, token_id: TokenId) -> Result<Amount, Error> {
         match self.listings.get(&token_id).await? {
-            Some(price) => Ok(price),
+            Some(Listing::FixedPrice { price }) => Ok(price),
+            Some(Listing::Auction(_)) => Err(Error::TokenIsAuctionedError),
             None => Err(Error::TokenNotListedError),
         }
     }
-    /*async fn pay(&mut self, from: ChainId, amount: Amount, to: ChainId) -> Result<(), Self::Error> {
-        let call: logging_fungible::ApplicationCall = logging_fungible::ApplicationCall::Transfer {
-            owner: self.get_owner_of_chain(m4.original_chain),
-            amount: price,
-            destination: self.get_owner_of_chain(cur_owner),
-        };
-        self.call_application(Self::logging_fungible_id()?, &call, vec![]).await?;
-        Ok(())
-    }*/
+    /// Seller side of `Buy`: passes the royalty on `price` to the company that minted the
+    /// token, unless the company is the one selling. The sale is done by then, so a royalty
+    /// that cannot be paid stays with the seller and the royalty paid is zero.
+    async fn pay_royalty(&mut self, token_id: &TokenId, price: Amount, royalty_bps: u16) -> Amount {
+        if token_id.minted_chain == system_api::current_chain_id() {
+            return Amount::zero();
+        }
+        let royalty = royalty_share(price, royalty_bps);
+        match self.debit(royalty, token_id.minted_chain).await {
+            Ok(()) => royalty,
+            Err(error) => {
+                info!("royalty of {} on {:?} not paid: {:?}", royalty, token_id, error);
+                Amount::zero()
+            }
+        }
+    }
+    /// Books `amount` into `balance`. With a fungible token application the tokens themselves
+    /// were already moved into this application's account by the `debit` or `deposit_from`
+    /// that pairs with it.
     async fn credit(&mut self, amount: Amount) -> Result<(), Error> {
         let mut balance = self.balance().await;
         balance.saturating_add_assign(amount);
@@ -1078,77 +1916,170 @@ impl Aqueduct {
         Ok(())
     }
 
+    /// Takes `amount` out of `balance` for the `credit` on chain `to`. With a fungible token
+    /// application the tokens move from this application's account here to its account on `to`.
     async fn debit(
         &mut self,
         amount: Amount,
+        to: ChainId,
     ) -> Result<(), Error> {
-        let mut balance = self.balance().await;
-        balance
-            .try_sub_assign(amount)
+        let balance = checked_debit(self.balance().await, amount)
             .map_err(|_| Error::InsufficientBalanceError)?;
+        if let Some(fungible_id) = Self::fungible_id()? {
+            let call = fungible::ApplicationCall::Transfer {
+                owner: Self::fungible_account_owner(),
+                amount,
+                destination: fungible::Destination::Account(fungible::Account {
+                    chain_id: to,
+                    owner: Self::fungible_account_owner(),
+                }),
+            };
+            self.call_application(true, fungible_id, &call, vec![]).await?;
+        }
         self.balance.set(balance);
         Ok(())
     }
 
+    /// Moves `amount` fungible tokens from `owner` into this application's account on this
+    /// chain, ahead of the `credit` booking them. Nothing to move without a fungible token
+    /// application.
+    async fn deposit_from(&mut self, owner: Option<Owner>, amount: Amount) -> Result<(), Error> {
+        if let Some(fungible_id) = Self::fungible_id()? {
+            let owner = owner.ok_or(Error::UnauthenticatedDepositError)?;
+            let call = fungible::ApplicationCall::Transfer {
+                owner: fungible::AccountOwner::User(owner),
+                amount,
+                destination: fungible::Destination::Account(fungible::Account {
+                    chain_id: system_api::current_chain_id(),
+                    owner: Self::fungible_account_owner(),
+                }),
+            };
+            self.call_application(true, fungible_id, &call, vec![]).await?;
+        }
+        Ok(())
+    }
+
+    /// Takes `amount` out of `balance` and out of the application, see `Beneficiary`.
+    async fn withdraw(&mut self, amount: Amount, to: Beneficiary, signer: Option<Owner>) -> Result<(), Error> {
+        let fungible_id = Self::fungible_id()?;
+        let withdrawal = plan_withdrawal(self.balance().await, amount, &to, signer, system_api::current_chain_id(), fungible_id.is_some())?;
+        if let (Withdrawal::Fungible { owner, .. }, Some(fungible_id)) = (withdrawal, fungible_id) {
+            let call = fungible::ApplicationCall::Transfer {
+                owner: Self::fungible_account_owner(),
+                amount,
+                destination: fungible::Destination::Account(fungible::Account {
+                    chain_id: to.chain_id,
+                    owner: fungible::AccountOwner::User(owner),
+                }),
+            };
+            self.call_application(true, fungible_id, &call, vec![]).await?;
+        }
+        self.balance.set(withdrawal.rest());
+        Ok(())
+    }
+
+    fn fungible_account_owner() -> fungible::AccountOwner {
+        fungible::AccountOwner::Application(system_api::current_application_id())
+    }
+
+    /// Brings views written by older versions of the application up to `SCHEMA_VERSION`.
+    async fn migrate(&mut self) -> Result<(), Error> {
+        let version = *self.schema_version.get();
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+        // the readers in state.rs know every older layout, migrating rewrites what they return
+        if version < 2 {
+            // 0 -> 2: segments became payout curves, old segments are power curves,
+            // 1 -> 2: curve segments still stored period and milestone in milliseconds
+            for stream_id in self.stream_ids().await? {
+                if let Some(stream) = self.stream(&stream_id).await? {
+                    self.nfts.insert(&stream_id, stream)?;
+                }
+            }
+            self.legacy_nfts.clear();
+        }
+        if version < 3 {
+            // 2 -> 3: owned tokens are keyed by their id
+            for token in self.tokens().await? {
+                self.owned_tokens.insert(&token.id, token.metadata)?;
+            }
+            self.legacy_owned_tokens.clear();
+        }
+        if version < 4 {
+            // 3 -> 4: listings can be auctions, the old ones had a fixed price
+            for (token_id, listing) in self.token_listings().await? {
+                self.listings.insert(&token_id, listing)?;
+            }
+            self.legacy_listings.clear();
+        }
+        if version < 5 {
+            // 4 -> 5: pending flows are indexed
+            for id in self.pending_flows().await? {
+                self.pending_flow_ids.insert(&id)?;
+            }
+        }
+        self.schema_version.set(SCHEMA_VERSION);
+        Ok(())
+    }
+
     fn logger_id() -> Result<ApplicationId<logger::LoggerAbi>, Error> {
         Ok(bcs::from_bytes::<ApplicationId>(&hex::decode(Self::parameters()?.logger_application_id)?)?.with_abi::<logger::LoggerAbi>())
     }
 
+    /// The `RevenueSource` picked by `Parameter::revenue_source`.
+    fn revenue_source(&mut self) -> Result<Box<dyn RevenueSource<Error> + Send + '_>, Error> {
+        revenue::revenue_source(Self::parameters()?.revenue_source, system_api::current_chain_id(), self)
+    }
+
+    fn fungible_id() -> Result<Option<ApplicationId<fungible::FungibleTokenAbi>>, Error> {
+        match Self::parameters()?.fungible_application_id {
+            Some(id) => Ok(Some(bcs::from_bytes::<ApplicationId>(&hex::decode(id)?)?.with_abi::<fungible::FungibleTokenAbi>())),
+            None => Ok(None),
+        }
+    }
+
+
+    /// Company side of `Operation::Receive`: pays whatever is due on the stream, plus earlier
+    /// arrears, out of `balance` to the `holder` chain and remembers the part the balance could
+    /// not cover.
+    async fn pay_out(&mut self, stream_id: StreamId, holder: ChainId) -> Result<Payout, Error> {
+        let mut owed = self.arrears.get(&stream_id).await?.unwrap_or_else(Amount::zero);
+        owed.saturating_add_assign(self.handle_receive(stream_id.clone()).await?);
+        let payout = Payout::new(owed, self.balance().await);
+        self.debit(payout.paid, holder).await?;
+        if payout.shortfall() == Amount::zero() {
+            self.arrears.remove(&stream_id)?;
+        } else {
+            self.arrears.insert(&stream_id, payout.shortfall())?;
+        }
+        Ok(payout)
+    }
+
+    /// Holder side of `Operation::Receive`.
+    async fn receive_payout(&mut self, token_id: TokenId, payout: Payout) -> Result<FlowPayload, Error> {
+        self.credit(payout.paid).await?;
+        self.last_payouts.insert(&token_id, payout.clone())?;
+        Ok(FlowPayload::Payout(payout))
+    }
 
-    async fn handle_receive(&mut self, stream_id: StreamId, to: ChainId) -> Result<Amount, Error> {
+    async fn handle_receive(&mut self, stream_id: StreamId) -> Result<Amount, Error> {
         let stream = self.nfts.get(&stream_id).await?;
         let mut amount_to_give = Amount::zero();
         if let Some(stream) = stream {
-            let mut cur_milestone = stream.milestones_received;
-            let mut cur_period = stream.periods_received;
-            let mut time = stream.created;
-            if cur_milestone >= stream.segments.len() as u64 {
-                return Ok(amount_to_give);
-            }
-            if cur_milestone > 0 { time += stream.segments[(cur_milestone - 1) as usize].milestone; }
-            time += stream.segments[cur_milestone as usize].period * cur_period;
-            let mut prev_milestone = 0;
-            while time < system_api::current_system_time().micros() && cur_milestone < stream.segments.len() as u64 {
-                let mut endtime = time + stream.segments[cur_milestone as usize].period;
-                if endtime > stream.segments[cur_milestone as usize].milestone {
-                    endtime = stream.segments[cur_milestone as usize].milestone;
-                }
-                for keyword in &stream.keywords {
-                    let call = logger::ApplicationCall::Query {
-                        log_type: Some(logger::LogType::OperationExecutionStart),
-                        keyword: keyword.to_string(),
-                        app: None,
-                        app_name: None,
-                        timestamp: Some((Timestamp::from(time), Timestamp::from(endtime))),
-                        function_name: None,
-                    };
-                    if let Ok((log, _)) = self.call_application(true, Self::logger_id()?, &call, vec![]).await {
-                        for log_statement in log {
-                            if let Ok(Operation::Income { amount, keyword }) = serde_json::from_str::<Operation>(&log_statement.log) {
-                                let cons: f64 = (stream.segments[cur_milestone as usize].constant as f64 ) / 1000000000000000000.;
-                                let fact: f64 = (stream.segments[cur_milestone as usize].factor as f64 ) / 1000000000000000000.;
-                                let expo: f64 = (stream.segments[cur_milestone as usize].exponent as f64 ) / 1000000000000000000.;
-                                let var1=(((time - prev_milestone) as f64) / ((stream.segments[cur_milestone as usize].milestone - prev_milestone) as f64));
-                                amount_to_give.saturating_add_assign(amount.saturating_mul((cons + fact * var1.powf(expo)) as u128));
-                            }
-                        }
-                    }
-                }
-                time = endtime;
-                cur_period += 1;
-                if time == stream.segments[cur_milestone as usize].milestone {
-                    prev_milestone = stream.segments[cur_milestone as usize].milestone;
-                    cur_milestone += 1;
-                    cur_period = 0;
-                }
+            let now = StreamTime::from_absolute(system_api::current_system_time(), stream.created);
+            let due = stream.due_periods(now)?;
+            for period in &due.periods {
+                let start = period.start.to_absolute(stream.created);
+                let end = period.end.to_absolute(stream.created);
+                let income = self.revenue_source()?.income(&stream.keywords, start, end).await?;
+                amount_to_give.saturating_add_assign(payout_share(income, period.multiplier)?);
             }
             self.nfts.insert(&stream_id, Stream {
-                keywords: stream.keywords,
-                segments: stream.segments,
-                created: stream.created,
-                milestones_received: cur_milestone,
-                periods_received: cur_period,
-            });
+                milestones_received: due.milestones_received,
+                periods_received: due.periods_received,
+                ..stream
+            })?;
             Ok(amount_to_give)
         } else {
             Err(Error::CompanyDoesntHaveStreamError)
@@ -1156,12 +2087,32 @@ impl Aqueduct {
     }
 }
 
-#[derive(Serialize)]
-struct OnERC721Received {
-    from: ChainId,
-    to: ChainId,
-    token_id: TokenId,
-    data: Vec<u8>,
+#[async_trait]
+impl RevenueBackend for &mut Aqueduct {
+    type Error = Error;
+
+    fn state(&self) -> &Aqueduct {
+        self
+    }
+
+    async fn query_logger(
+        &mut self,
+        keyword: &str,
+        app: Option<ApplicationId>,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<String>, Error> {
+        let call = logger::ApplicationCall::Query {
+            log_type: Some(logger::LogType::OperationExecutionStart),
+            keyword: keyword.to_string(),
+            app,
+            app_name: None,
+            timestamp: Some((start, end)),
+            function_name: None,
+        };
+        let (log, _) = self.call_application(true, Aqueduct::logger_id()?, &call, vec![]).await?;
+        Ok(log.into_iter().map(|log_statement| log_statement.log).collect())
+    }
 }
 
 /// An error that can occur during the contract execution.
@@ -1181,17 +2132,83 @@ pub enum Error {
     #[error("token is not listed by owner")]
     TokenNotListedError,
 
+    #[error("the listed price changed while buying")]
+    PriceChangedError,
+
     #[error("insufficient balance")]
     InsufficientBalanceError,
 
+    #[error("a fungible token deposit needs an authenticated signer to take the tokens from")]
+    UnauthenticatedDepositError,
+
+    #[error("a fungible token withdrawal needs an owner or an authenticated signer to send the tokens to")]
+    NoWithdrawalOwnerError,
+
+    #[error("native tokens never leave the chain balance, they can only be withdrawn on this chain")]
+    NativeWithdrawalToOtherChainError,
+
     #[error("company did not mint a nft with this streamid")]
     CompanyDoesntHaveStreamError,
 
+    #[error("a royalty cannot exceed 10000 basis points")]
+    RoyaltyTooHighError,
+
+    #[error("auction {0}")]
+    AuctionError(#[from] AuctionError),
+
+    #[error("the token is auctioned, bid on it instead")]
+    TokenIsAuctionedError,
+
+    #[error("the auction holds a bid, close it instead")]
+    AuctionHasBidsError,
+
+    #[error("the offer has expired")]
+    OfferExpiredError,
+
+    #[error("this chain has no offer escrowed for this token")]
+    NoEscrowForOfferError,
+
+    #[error("this chain has no listing for this token")]
+    ListingNotFound,
+
+    #[error("only streams whose token was burned can be retired")]
+    StreamStillHeldError,
+
     #[error("minted chain doesnt list owner of a token it minted")]
     NoOwnerInMintedError,
 
     #[error("this chain dopes not own this tojken")]
     ThisChainDoesNotOwnThisTokenError,
+
+    #[error("the sending chain is neither approved for this token nor an operator of its owner")]
+    NotAnOperatorNorApproved,
+
+    #[error("the recipient did not acknowledge the token")]
+    AfterTransferCheckFailed,
+
+    #[error("a token cannot be transferred to the chain owning it")]
+    TransferToSelfError,
+
+    #[error("the flow was recovered after its deadline")]
+    FlowRecoveredError,
+
+    #[error("the token's owner on record is not the chain it moves from")]
+    OwnerChangedError,
+
+    #[error("no flow with this id started on this chain")]
+    FlowNotFoundError,
+
+    #[error("the flow already ended")]
+    FlowNotPendingError,
+
+    #[error("the flow has not reached its deadline yet")]
+    FlowNotExpiredError,
+
+    #[error("payout curve arithmetic failed {0}")]
+    FixedPointError(#[from] fixed::FixedPointError),
+
+    #[error("invalid stream schedule {0}")]
+    ScheduleError(#[from] ScheduleError),
 //m4 errors start
 
     #[error("original operation somehow got lost during messages")]
@@ -1231,3 +2248,245 @@ pub enum Error {
     NoNameInCargoToml,
 
 }
+
+impl From<WithdrawalError> for Error {
+    fn from(error: WithdrawalError) -> Self {
+        match error {
+            WithdrawalError::InsufficientBalance(_) => Error::InsufficientBalanceError,
+            WithdrawalError::NativeToOtherChain => Error::NativeWithdrawalToOtherChainError,
+            WithdrawalError::NoOwner => Error::NoWithdrawalOwnerError,
+        }
+    }
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::BcsError(_) => ErrorCode::Bcs,
+            Error::JsonError(_) => ErrorCode::Json,
+            Error::ViewError(_) => ErrorCode::View,
+            Error::TokenNotListedError | Error::ListingNotFound => ErrorCode::TokenNotListed,
+            Error::TokenIsAuctionedError | Error::AuctionHasBidsError => ErrorCode::TokenIsAuctioned,
+            Error::PriceChangedError => ErrorCode::PriceChanged,
+            Error::FlowRecoveredError => ErrorCode::TimedOut,
+            Error::InsufficientBalanceError => ErrorCode::InsufficientBalance,
+            Error::NoOwnerInMintedError => ErrorCode::NoOwnerInMinted,
+            Error::ThisChainDoesNotOwnThisTokenError | Error::OwnerChangedError => ErrorCode::NotOwner,
+            Error::NotAnOperatorNorApproved => ErrorCode::NotAnOperatorNorApproved,
+            Error::AfterTransferCheckFailed => ErrorCode::Refused,
+            Error::CompanyDoesntHaveStreamError => ErrorCode::CompanyDoesntHaveStream,
+            Error::FixedPointError(_) => ErrorCode::FixedPoint,
+            Error::M4MismatchedOperationError => ErrorCode::MismatchedOperation,
+            Error::M4MismatchedOperationContextError => ErrorCode::MismatchedOperationContext,
+            Error::M4MismatchedMessageError => ErrorCode::MismatchedMessage,
+            Error::M4MismatchedMessageContextError => ErrorCode::MismatchedMessageContext,
+            Error::NoEscrowForOfferError => ErrorCode::NoEscrow,
+            Error::FlowNotPendingError => ErrorCode::FlowNotPending,
+            // no code of their own: mostly operation or start-up errors a flow step does not hit
+            Error::UnauthenticatedDepositError
+            | Error::NoWithdrawalOwnerError
+            | Error::NativeWithdrawalToOtherChainError
+            | Error::RoyaltyTooHighError
+            | Error::AuctionError(_)
+            | Error::OfferExpiredError
+            | Error::StreamStillHeldError
+            | Error::TransferToSelfError
+            | Error::FlowNotFoundError
+            | Error::FlowNotExpiredError
+            | Error::ScheduleError(_)
+            | Error::Utf8Error(_)
+            | Error::FindCrateError(_)
+            | Error::HexError(_)
+            | Error::IoError(_)
+            | Error::TomlDeError(_)
+            | Error::NoRequiredIdsError
+            | Error::NotFoundManifestDir
+            | Error::NoNameInCargoToml => ErrorCode::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Aqueduct;
+    use crate::state::SCHEMA_VERSION;
+    use aqueduct::{
+        ErrorCode, FlowId, FlowKind, FlowOutcome, FlowState, Listing, Message, Operation, TokenId,
+        TokenMetadata, FLOW_TIMEOUT_MICROS, M4,
+    };
+    use futures::FutureExt;
+    use linera_sdk::{
+        base::{Amount, BlockHeight, ChainId, CryptoHash, MessageId, Timestamp},
+        contract::system_api,
+        test,
+        views::ViewStorageContext,
+        ExecutionResult, MessageContext, OperationContext,
+    };
+    use linera_views::views::View;
+    use std::{fmt::Debug, future::Future};
+    use webassembly_test::webassembly_test;
+
+    #[webassembly_test]
+    fn redelivered_payments_are_credited_once() {
+        let (seller, buyer) = (ChainId::root(0), ChainId::root(1));
+        let mut app = create_app(seller, Timestamp::from(0));
+        let token_id = TokenId {
+            minted_chain: seller,
+            index: 0,
+        };
+        let message = Message::Buy_ReceivePayment {
+            m4: buy_step(buyer, &token_id, 7),
+            cur_owner: seller,
+            price: Amount::from_tokens(5),
+            royalty_bps: 0,
+        };
+
+        receive(&mut app, buyer, message.clone());
+        assert_eq!(*app.balance.get(), Amount::from_tokens(5));
+        assert!(receive(&mut app, buyer, message).is_empty());
+        assert_eq!(*app.balance.get(), Amount::from_tokens(5));
+    }
+
+    #[webassembly_test]
+    fn recovery_after_the_deadline_refunds_the_escrow() {
+        let (seller, buyer) = (ChainId::root(0), ChainId::root(1));
+        let mut app = create_app(buyer, Timestamp::from(0));
+        let token_id = TokenId {
+            minted_chain: seller,
+            index: 0,
+        };
+        let flow_id = buy_step(buyer, &token_id, 0).flow_id;
+        let flow = FlowState::new(
+            flow_id.clone(),
+            FlowKind::Buy,
+            "Buy_GetOwner",
+            Timestamp::from(0),
+        );
+        app.flows.insert(&flow_id, flow).unwrap();
+        app.pending_flow_ids.insert(&flow_id).unwrap();
+        app.balance.set(Amount::from_tokens(5));
+        let escrowed = Message::Buy_BackToPay {
+            m4: buy_step(buyer, &token_id, 3),
+            cur_owner: seller,
+            price: Amount::from_tokens(5),
+            royalty_bps: 0,
+        };
+        receive(&mut app, seller, escrowed);
+        assert_eq!(*app.balance.get(), Amount::zero());
+
+        test::mock_system_timestamp(Timestamp::from(FLOW_TIMEOUT_MICROS));
+        now(app.recover_flow(flow_id.clone()));
+        assert_eq!(*app.balance.get(), Amount::from_tokens(5));
+        assert_eq!(now(app.escrow.get(&token_id)), None);
+        let flow = now(app.flows.get(&flow_id)).unwrap();
+        assert_eq!(flow.outcome, Some(FlowOutcome::Err(ErrorCode::TimedOut)));
+    }
+
+    #[webassembly_test]
+    fn recovery_after_the_deadline_restores_the_sold_token() {
+        // the seller minted the token, so it also answers as the minting chain
+        let (seller, buyer) = (ChainId::root(0), ChainId::root(1));
+        let mut app = create_app(seller, Timestamp::from(0));
+        let token_id = TokenId {
+            minted_chain: seller,
+            index: 0,
+        };
+        let metadata = TokenMetadata {
+            name: "stream".to_string(),
+            description: String::new(),
+            image: String::new(),
+        };
+        let price = Amount::from_tokens(5);
+        app.owned_tokens.insert(&token_id, metadata).unwrap();
+        app.current_owner_minted.insert(&token_id, seller).unwrap();
+        app.listings
+            .insert(&token_id, Listing::FixedPrice { price })
+            .unwrap();
+        let sold = Message::Buy_TransferFrom {
+            m4: buy_step(buyer, &token_id, 4),
+            cur_owner: seller,
+            price,
+            royalty_bps: 0,
+        };
+        let late_update = receive(&mut app, buyer, sold).remove(0);
+        assert!(!now(app.check_own(token_id.clone())));
+
+        test::mock_system_timestamp(Timestamp::from(FLOW_TIMEOUT_MICROS));
+        let flow_id = buy_step(buyer, &token_id, 0).flow_id;
+        let recover_token = sent(now(app.recover_flow(flow_id))).remove(0);
+        let recovered = receive(&mut app, seller, recover_token).remove(0);
+        assert!(receive(&mut app, seller, recovered).is_empty());
+        assert!(now(app.check_own(token_id.clone())));
+
+        // the sale the minting chain never recorded is dropped once it arrives
+        assert!(receive(&mut app, seller, late_update).is_empty());
+        assert_eq!(now(app.current_owner_minted.get(&token_id)), Some(seller));
+    }
+
+    fn create_app(chain_id: ChainId, time: Timestamp) -> Aqueduct {
+        test::mock_key_value_store();
+        test::mock_chain_id(chain_id);
+        test::mock_system_timestamp(time);
+        let mut app = now(Aqueduct::load(ViewStorageContext::default()));
+        app.schema_version.set(SCHEMA_VERSION);
+        app
+    }
+
+    /// Runs `future`, which never waits with the mocked system API.
+    fn now<T, E: Debug>(future: impl Future<Output = Result<T, E>>) -> T {
+        future
+            .now_or_never()
+            .expect("the mocked system API never waits")
+            .unwrap()
+    }
+
+    /// Handles `message` from `sender`, returning the messages it sends on.
+    fn receive(app: &mut Aqueduct, sender: ChainId, message: Message) -> Vec<Message> {
+        sent(now(app.receive_message(&message_context(sender), message)))
+    }
+
+    fn sent(result: ExecutionResult<Message>) -> Vec<Message> {
+        result
+            .messages
+            .into_iter()
+            .map(|(_, _, message)| message)
+            .collect()
+    }
+
+    /// Step `step` of a Buy of `token_id` started on `buyer`.
+    fn buy_step(buyer: ChainId, token_id: &TokenId, step: u64) -> M4 {
+        M4 {
+            original_chain: buyer,
+            original_operation: Some(Operation::Buy {
+                token_id: token_id.clone(),
+            }),
+            original_ope_context: Some(OperationContext {
+                chain_id: buyer,
+                authenticated_signer: None,
+                height: BlockHeight(0),
+                index: 0,
+            }),
+            original_message: None,
+            original_msg_context: None,
+            flow_id: FlowId {
+                chain_id: buyer,
+                index: 0,
+            },
+            step,
+        }
+    }
+
+    fn message_context(sender: ChainId) -> MessageContext {
+        MessageContext {
+            chain_id: system_api::current_chain_id(),
+            authenticated_signer: None,
+            height: BlockHeight(0),
+            certificate_hash: CryptoHash::from([0; 4]),
+            message_id: MessageId {
+                chain_id: sender,
+                height: BlockHeight(0),
+                index: 0,
+            },
+        }
+    }
+}
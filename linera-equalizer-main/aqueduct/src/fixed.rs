@@ -0,0 +1,171 @@
+//! Deterministic fixed-point arithmetic where `ONE` (10^18) stands for 1.
+//!
+//! Everything here is plain integer math with checked operations so every
+//! validator computes bit-identical payouts. Results are truncated toward zero.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// 1.0 in 10^18 fixed-point.
+pub const ONE: i128 = 1_000_000_000_000_000_000;
+
+/// ln(2) in 10^18 fixed-point.
+const LN_2: i128 = 693_147_180_559_945_309;
+
+/// Below this exponent `exp` rounds to zero (e^-42 < 10^-18).
+const EXP_MIN: i128 = -42 * ONE;
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FixedPointError {
+    #[error("fixed-point overflow")]
+    Overflow,
+
+    #[error("fixed-point division by zero")]
+    DivisionByZero,
+
+    #[error("fractional power of a negative number")]
+    NegativeBase,
+}
+
+/// `a * b`, both fixed-point.
+pub fn mul(a: i128, b: i128) -> Result<i128, FixedPointError> {
+    // split both sides into whole and fractional parts so no partial product
+    // leaves i128 unless the result itself does
+    let (ah, al) = (a / ONE, a % ONE);
+    let (bh, bl) = (b / ONE, b % ONE);
+    ah.checked_mul(bh)
+        .and_then(|x| x.checked_mul(ONE))
+        .and_then(|x| x.checked_add(ah.checked_mul(bl)?))
+        .and_then(|x| x.checked_add(al.checked_mul(bh)?))
+        .and_then(|x| x.checked_add(al * bl / ONE))
+        .ok_or(FixedPointError::Overflow)
+}
+
+/// `a / b`, both fixed-point.
+pub fn div(a: i128, b: i128) -> Result<i128, FixedPointError> {
+    if b == 0 {
+        return Err(FixedPointError::DivisionByZero);
+    }
+    let high = (a / b).checked_mul(ONE).ok_or(FixedPointError::Overflow)?;
+    let low = (a % b).checked_mul(ONE).ok_or(FixedPointError::Overflow)? / b;
+    high.checked_add(low).ok_or(FixedPointError::Overflow)
+}
+
+/// `numerator / denominator` of two plain integers as a fixed-point fraction.
+pub fn ratio(numerator: u64, denominator: u64) -> Result<i128, FixedPointError> {
+    div(numerator as i128, denominator as i128)
+}
+
+/// Natural logarithm of a strictly positive fixed-point number.
+pub fn ln(x: i128) -> Result<i128, FixedPointError> {
+    if x <= 0 {
+        return Err(FixedPointError::NegativeBase);
+    }
+    // x = m * 2^k with m in [1, 2)
+    let mut k: i128 = 0;
+    let mut m = x;
+    while m >= 2 * ONE {
+        m /= 2;
+        k += 1;
+    }
+    while m < ONE {
+        m *= 2;
+        k -= 1;
+    }
+    // ln(m) = 2 * atanh(z) = 2 * (z + z^3/3 + z^5/5 + ...), z = (m - 1) / (m + 1) <= 1/3
+    let z = div(m - ONE, m + ONE)?;
+    let z2 = mul(z, z)?;
+    let mut power = z;
+    let mut sum: i128 = 0;
+    let mut n: i128 = 1;
+    while power != 0 {
+        sum += power / n;
+        power = mul(power, z2)?;
+        n += 2;
+    }
+    k.checked_mul(LN_2)
+        .and_then(|l| l.checked_add(2 * sum))
+        .ok_or(FixedPointError::Overflow)
+}
+
+/// e raised to a fixed-point power.
+pub fn exp(x: i128) -> Result<i128, FixedPointError> {
+    if x < EXP_MIN {
+        return Ok(0);
+    }
+    // x = k * ln(2) + r with r in [0, ln(2))
+    let k = x.div_euclid(LN_2);
+    let r = x.rem_euclid(LN_2);
+    // e^r by its taylor series, every term is positive and shrinking
+    let mut term = ONE;
+    let mut sum = ONE;
+    let mut n: i128 = 1;
+    while term != 0 {
+        term = mul(term, r)? / n;
+        sum += term;
+        n += 1;
+    }
+    if k >= 0 {
+        if k >= 127 {
+            return Err(FixedPointError::Overflow);
+        }
+        sum.checked_mul(1 << k).ok_or(FixedPointError::Overflow)
+    } else if k <= -127 {
+        Ok(0)
+    } else {
+        Ok(sum >> -k)
+    }
+}
+
+/// `base` raised to the `exponent`, both fixed-point.
+///
+/// Whole exponents go through exponentiation by squaring and accept any base,
+/// fractional exponents are computed as `exp(exponent * ln(base))`.
+pub fn pow(base: i128, exponent: i128) -> Result<i128, FixedPointError> {
+    if exponent == 0 {
+        return Ok(ONE);
+    }
+    if exponent % ONE == 0 {
+        let result = powi(base, (exponent / ONE).unsigned_abs())?;
+        return if exponent < 0 { div(ONE, result) } else { Ok(result) };
+    }
+    match base {
+        0 if exponent > 0 => Ok(0),
+        0 => Err(FixedPointError::DivisionByZero),
+        b if b < 0 => Err(FixedPointError::NegativeBase),
+        ONE => Ok(ONE),
+        _ => exp(mul(exponent, ln(base)?)?),
+    }
+}
+
+fn powi(mut base: i128, mut exponent: u128) -> Result<i128, FixedPointError> {
+    let mut result = ONE;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, base)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = mul(base, base)?;
+        }
+    }
+    Ok(result)
+}
+
+/// `amount * multiplier` where `amount` is a plain integer (e.g. atto tokens)
+/// and `multiplier` is a non-negative fixed-point number, rounded down.
+pub fn scale(amount: u128, multiplier: u128) -> Result<u128, FixedPointError> {
+    let one = ONE as u128;
+    let high = (amount / one).checked_mul(multiplier).ok_or(FixedPointError::Overflow)?;
+    let low = (amount % one)
+        .checked_mul(multiplier)
+        .ok_or(FixedPointError::Overflow)?
+        / one;
+    high.checked_add(low).ok_or(FixedPointError::Overflow)
+}
+
+/// Decimal representation of a non-negative fixed-point number, e.g. `0.500000000000000000`.
+pub fn to_decimal(value: u128) -> String {
+    let one = ONE as u128;
+    format!("{}.{:018}", value / one, value % one)
+}
@@ -1,8 +1,11 @@
 #![allow(non_camel_case_types)]
-use linera_sdk::base::{ContractAbi, ServiceAbi, ChainId, Amount};
+pub mod fixed;
+
+use linera_sdk::base::{ContractAbi, ServiceAbi, ApplicationId, ChainId, Amount, Owner, Timestamp};
 use linera_sdk::{OperationContext, MessageContext, ExecutionResult};
-use serde::{Serialize, Deserialize};
-use async_graphql::{scalar, SimpleObject, InputObject, Request, Response, Object};
+use serde::{Serialize, Deserialize, Deserializer};
+use async_graphql::{scalar, SimpleObject, InputObject, Request, Response, Object, Enum};
+use thiserror::Error;
 
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct AqueductAbi;
@@ -21,7 +24,56 @@ impl ContractAbi for AqueductAbi {
 impl ServiceAbi for AqueductAbi {
     type Query = Request;
     type QueryResponse = Response;
+    type Parameters = Parameter;
+}
+
+/// What an application registered with `Operation::RegisterReceiver` has to answer when
+/// called with an `OnERC721Received`. Anything but `ERC721_RECEIVED` refuses the token.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Erc721ReceiverAbi;
+
+impl ContractAbi for Erc721ReceiverAbi {
+    type InitializationArgument = ();
     type Parameters = ();
+    type Operation = ();
+    type ApplicationCall = OnERC721Received;
+    type Message = ();
+    type SessionCall = ();
+    type Response = [u8; 4];
+    type SessionState = ();
+}
+
+/// `bytes4(keccak256("onERC721Received(address,address,uint256,bytes)"))`, as in ERC-721.
+pub const ERC721_RECEIVED: [u8; 4] = [0x15, 0x0b, 0x7a, 0x02];
+
+/// What became of asking a chain's receiver about an arriving token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiverAnswer {
+    /// no receiver registered, every token is welcome
+    Unregistered,
+    Answered([u8; 4]),
+    /// the registered receiver could not be called
+    CallFailed,
+}
+
+impl ReceiverAnswer {
+    /// Only `ERC721_RECEIVED` from a registered receiver accepts, a failed call refuses.
+    pub fn accepts(&self) -> bool {
+        match self {
+            ReceiverAnswer::Unregistered => true,
+            ReceiverAnswer::Answered(answer) => *answer == ERC721_RECEIVED,
+            ReceiverAnswer::CallFailed => false,
+        }
+    }
+}
+
+/// A token arriving on a chain, passed to its receiving application.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct OnERC721Received {
+    pub from: ChainId,
+    pub to: ChainId,
+    pub token_id: TokenId,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -49,19 +101,296 @@ pub struct Token {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Parameter {
     pub logger_application_id: String,
+    #[serde(default)]
+    pub revenue_source: RevenueSourceKind,
+    /// Fungible token application `balance` is held in, hex like `logger_application_id`.
+    /// Without one, `balance` is bookkeeping internal to the application.
+    #[serde(default)]
+    pub fungible_application_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Where `Operation::Receive` finds the income a stream pays a share of.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub enum RevenueSourceKind {
+    /// `Operation::Income` as seen by the logger, the on-chain ledger when the logger fails
+    #[default]
+    Logger,
+    /// `Operation::Income` as recorded in the company chain's own ledger
+    Ledger,
+    /// transfers into the company chain made with a fungible token application that logs
+    /// through the logger, whatever the stream keywords
+    FungibleTransfers {
+        application_id: String, //hex, like logger_application_id
+    },
+}
+
+/// Span of time measured from a stream's `created` timestamp, in microseconds like `Timestamp`.
+///
+/// Segment periods and milestones are all `StreamTime`s, use `to_absolute`/`from_absolute`
+/// whenever they meet the clock or the logger.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamTime(u64);
+
+scalar!(StreamTime);
+
+impl StreamTime {
+    pub const ZERO: StreamTime = StreamTime(0);
+
+    pub fn from_micros(micros: u64) -> Self {
+        StreamTime(micros)
+    }
+
+    pub fn from_millis(millis: u64) -> Self {
+        StreamTime(millis.saturating_mul(1000))
+    }
+
+    pub fn micros(self) -> u64 {
+        self.0
+    }
+
+    /// How long after `created` the absolute time `at` is, 0 if it is before.
+    pub fn from_absolute(at: Timestamp, created: Timestamp) -> Self {
+        StreamTime(at.micros().saturating_sub(created.micros()))
+    }
+
+    /// The absolute time this far after `created`.
+    pub fn to_absolute(self, created: Timestamp) -> Timestamp {
+        Timestamp::from(created.micros().saturating_add(self.0))
+    }
+
+    pub fn saturating_add(self, other: StreamTime) -> Self {
+        StreamTime(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: StreamTime) -> Self {
+        StreamTime(self.0.saturating_sub(other.0))
+    }
+
+    pub fn saturating_mul(self, times: u64) -> Self {
+        StreamTime(self.0.saturating_mul(times))
+    }
+}
+
+/// Payout multiplier as a function of `t`, how far into its milestone window a period
+/// starts (0 at the previous milestone, 10^18 at this one). All values are 10^18 fixed-point.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum PayoutCurve {
+    Constant {
+        value: i64,
+    },
+    /// straight line from `start` at t = 0 to `end` at t = 1
+    Linear {
+        start: i64,
+        end: i64,
+    },
+    /// `constant + factor * t^exponent`, the only shape older segments could express
+    Power {
+        constant: i64,
+        factor: i64,
+        exponent: i64,
+    },
+    /// `initial * e^(-rate * t)`
+    Decay {
+        initial: i64,
+        rate: i64,
+    },
+    /// value of the last step whose `from` is at or before t, 0 before the first step
+    Step {
+        steps: Vec<CurveStep>,
+    },
+    /// 0 until t reaches `at`, then follows `curve`
+    Cliff {
+        at: i64,
+        curve: Box<PayoutCurve>,
+    },
+    /// `curve` but never more than `cap`
+    Capped {
+        cap: i64,
+        curve: Box<PayoutCurve>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct CurveStep {
+    pub from: i64,
+    pub value: i64,
+}
+
+impl PayoutCurve {
+    /// Raw (possibly negative) multiplier at `t`.
+    pub fn evaluate(&self, t: i128) -> Result<i128, fixed::FixedPointError> {
+        use fixed::FixedPointError::Overflow;
+        match self {
+            PayoutCurve::Constant { value } => Ok(*value as i128),
+            PayoutCurve::Linear { start, end } => {
+                let slope = fixed::mul(*end as i128 - *start as i128, t)?;
+                (*start as i128).checked_add(slope).ok_or(Overflow)
+            }
+            PayoutCurve::Power { constant, factor, exponent } => {
+                let curve = fixed::mul(*factor as i128, fixed::pow(t, *exponent as i128)?)?;
+                (*constant as i128).checked_add(curve).ok_or(Overflow)
+            }
+            PayoutCurve::Decay { initial, rate } => {
+                let decay = fixed::exp(-fixed::mul(*rate as i128, t)?)?;
+                fixed::mul(*initial as i128, decay)
+            }
+            PayoutCurve::Step { steps } => Ok(steps
+                .iter()
+                .filter(|step| step.from as i128 <= t)
+                .max_by_key(|step| step.from)
+                .map_or(0, |step| step.value as i128)),
+            PayoutCurve::Cliff { at, curve } => {
+                if t < *at as i128 { Ok(0) } else { curve.evaluate(t) }
+            }
+            PayoutCurve::Capped { cap, curve } => Ok(curve.evaluate(t)?.min(*cap as i128)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
 pub struct Segment {
-    pub period: u64,   //in milliseconds
-    pub constant: i64,  //1 is 10^18
-    pub factor: i64,    //1 is 10^18
-    pub exponent: i64,  //1 is 10^18
-    pub milestone: u64,//in milliseconds
+    #[serde(rename = "period_micros")]
+    pub period: StreamTime,
+    #[serde(rename = "milestone_micros")]
+    pub milestone: StreamTime,  //end of this segment, relative to the stream's creation
+    pub curve: PayoutCurve,
 }
 
 scalar!(Segment);
 
+impl Segment {
+    /// Payout multiplier (10^18 fixed-point) for a period starting `elapsed` into a
+    /// milestone window of length `window`. Negative values clamp to 0.
+    pub fn multiplier(&self, elapsed: StreamTime, window: StreamTime) -> Result<u128, fixed::FixedPointError> {
+        let t = fixed::ratio(elapsed.micros(), window.micros())?;
+        Ok(self.curve.evaluate(t)?.max(0) as u128)
+    }
+}
+
+/// Exact share of `income` paid out under a 10^18 fixed-point `multiplier`, rounded down.
+pub fn payout_share(income: Amount, multiplier: u128) -> Result<Amount, fixed::FixedPointError> {
+    Ok(Amount::from_atto(fixed::scale(u128::from(income), multiplier)?))
+}
+
+/// Segment as it was before payout curves, still accepted from json (graphql, logger)
+/// and still found in views written by older versions. Its json keys `period` and
+/// `milestone` are milliseconds, a `Segment` says `period_micros` and `milestone_micros`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LegacySegment {
+    #[serde(rename = "period")]
+    pub period_millis: u64,
+    pub constant: i64,
+    pub factor: i64,
+    pub exponent: i64,
+    #[serde(rename = "milestone")]
+    pub milestone_millis: u64,
+}
+
+scalar!(LegacySegment);
+
+impl From<LegacySegment> for Segment {
+    fn from(legacy: LegacySegment) -> Self {
+        Segment {
+            period: StreamTime::from_millis(legacy.period_millis),
+            milestone: StreamTime::from_millis(legacy.milestone_millis),
+            curve: PayoutCurve::Power {
+                constant: legacy.constant,
+                factor: legacy.factor,
+                exponent: legacy.exponent,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Segment")]
+struct CurveSegment {
+    #[serde(rename = "period_micros")]
+    period: StreamTime,
+    #[serde(rename = "milestone_micros")]
+    milestone: StreamTime,
+    curve: PayoutCurve,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnySegment {
+    Curve(CurveSegment),
+    Legacy(LegacySegment),
+}
+
+impl From<CurveSegment> for Segment {
+    fn from(segment: CurveSegment) -> Self {
+        Segment {
+            period: segment.period,
+            milestone: segment.milestone,
+            curve: segment.curve,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Segment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // untagged needs a self-describing format, bcs (views, messages) only ever sees the new layout
+        if deserializer.is_human_readable() {
+            Ok(match AnySegment::deserialize(deserializer)? {
+                AnySegment::Curve(segment) => segment.into(),
+                AnySegment::Legacy(legacy) => legacy.into(),
+            })
+        } else {
+            Ok(CurveSegment::deserialize(deserializer)?.into())
+        }
+    }
+}
+
+/// Why a list of segments cannot be used as a stream schedule.
+#[derive(Debug, Error, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleError {
+    #[error("schedule has no segments")]
+    EmptySchedule,
+
+    #[error("segment {0} has a zero period")]
+    ZeroPeriod(usize),
+
+    #[error("segment {0} milestone does not come after the previous milestone")]
+    NonMonotonicMilestones(usize),
+
+    #[error("segment {0} period does not fit within its milestone window")]
+    PeriodExceedsWindow(usize),
+
+    #[error("segment {0} payout curve cannot be evaluated: {1}")]
+    InvalidCurve(usize, fixed::FixedPointError),
+}
+
+/// Checks that every milestone comes after the previous one (the first after 0), that
+/// every period is non-zero and fits its milestone window, and that every curve can be
+/// evaluated at both ends of its window.
+pub fn validate_schedule(segments: &[Segment]) -> Result<(), ScheduleError> {
+    if segments.is_empty() {
+        return Err(ScheduleError::EmptySchedule);
+    }
+    let mut prev_milestone = StreamTime::ZERO;
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.period == StreamTime::ZERO {
+            return Err(ScheduleError::ZeroPeriod(index));
+        }
+        if segment.milestone <= prev_milestone {
+            return Err(ScheduleError::NonMonotonicMilestones(index));
+        }
+        let window = segment.milestone.saturating_sub(prev_milestone);
+        if segment.period > window {
+            return Err(ScheduleError::PeriodExceedsWindow(index));
+        }
+        for elapsed in [StreamTime::ZERO, window] {
+            segment
+                .multiplier(elapsed, window)
+                .map_err(|error| ScheduleError::InvalidCurve(index, error))?;
+        }
+        prev_milestone = segment.milestone;
+    }
+    Ok(())
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StreamId {
     pub company: ChainId,
@@ -81,11 +410,447 @@ pub struct Stream {
     pub keywords: Vec<String>,  //so you can have different streams like selling one for selling
                                 //product a and one for selling product b
     pub segments: Vec<Segment>,
-    pub created: u64,           //number of non-leap milliseconds since 1 1 1970 UTC
+    pub created: Timestamp,     //every StreamTime in the stream is relative to this
     pub milestones_received: u64,
     pub periods_received: u64,  //number of periods received this current milestone
 }
 
+/// A period of a stream that is over and can be paid out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuePeriod {
+    pub start: StreamTime,
+    pub end: StreamTime,
+    pub multiplier: u128,   //10^18 fixed-point
+}
+
+/// What receiving from a stream covers, and where its cursor ends up afterwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuePeriods {
+    pub periods: Vec<DuePeriod>,
+    pub milestones_received: u64,
+    pub periods_received: u64,
+}
+
+impl Stream {
+    /// Every period that ended by `now` and was not received yet, in order.
+    pub fn due_periods(&self, now: StreamTime) -> Result<DuePeriods, ScheduleError> {
+        // streams stored before mint validated schedules could still spin forever below
+        validate_schedule(&self.segments)?;
+        let mut due = DuePeriods {
+            periods: Vec::new(),
+            milestones_received: self.milestones_received,
+            periods_received: self.periods_received,
+        };
+        if due.milestones_received >= self.segments.len() as u64 {
+            return Ok(due);
+        }
+        let mut prev_milestone = if due.milestones_received > 0 {
+            self.segments[(due.milestones_received - 1) as usize].milestone
+        } else {
+            StreamTime::ZERO
+        };
+        let mut time = prev_milestone.saturating_add(
+            self.segments[due.milestones_received as usize].period.saturating_mul(due.periods_received),
+        );
+        while due.milestones_received < self.segments.len() as u64 {
+            let index = due.milestones_received as usize;
+            let segment = &self.segments[index];
+            let mut end = time.saturating_add(segment.period);
+            if end > segment.milestone {
+                end = segment.milestone;
+            }
+            // only periods that are over, later income would be missed otherwise
+            if end > now {
+                break;
+            }
+            let multiplier = segment
+                .multiplier(time.saturating_sub(prev_milestone), segment.milestone.saturating_sub(prev_milestone))
+                .map_err(|error| ScheduleError::InvalidCurve(index, error))?;
+            due.periods.push(DuePeriod { start: time, end, multiplier });
+            time = end;
+            due.periods_received += 1;
+            if time == segment.milestone {
+                prev_milestone = segment.milestone;
+                due.milestones_received += 1;
+                due.periods_received = 0;
+            }
+        }
+        Ok(due)
+    }
+}
+
+/// The parts of a fungible token operation, as logged in json, that
+/// `RevenueSourceKind::FungibleTransfers` reads.
+#[derive(Debug, Deserialize)]
+pub enum LoggedFungibleOperation {
+    Transfer {
+        amount: Amount,
+        target_account: LoggedFungibleAccount,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoggedFungibleAccount {
+    pub chain_id: ChainId,
+}
+
+impl LoggedFungibleOperation {
+    /// Amount a logged operation transferred to `chain_id`, if it is such a transfer.
+    pub fn received_by(log: &str, chain_id: ChainId) -> Option<Amount> {
+        match serde_json::from_str::<LoggedFungibleOperation>(log) {
+            Ok(LoggedFungibleOperation::Transfer { amount, target_account }) if target_account.chain_id == chain_id => Some(amount),
+            _ => None,
+        }
+    }
+}
+
+/// Width of the buckets `Operation::Income` is recorded in, one hour in microseconds.
+pub const REVENUE_BUCKET_MICROS: u64 = 3_600_000_000;
+
+/// Key of the on-chain revenue ledger: income for `keyword` in the bucket starting at
+/// `bucket * REVENUE_BUCKET_MICROS`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RevenueKey {
+    pub keyword: String,
+    pub bucket: u64,
+}
+
+scalar!(RevenueKey);
+
+impl RevenueKey {
+    pub fn at(keyword: String, time: Timestamp) -> Self {
+        RevenueKey { keyword, bucket: time.micros() / REVENUE_BUCKET_MICROS }
+    }
+
+    /// Keys of every bucket that closes within `(start, end]`. A bucket still open at `end`
+    /// can take more income after the window is paid out, so it counts in the window it closes
+    /// in; consecutive windows see each bucket exactly once.
+    pub fn range(keyword: &str, start: Timestamp, end: Timestamp) -> impl Iterator<Item = RevenueKey> + '_ {
+        let first = start.micros() / REVENUE_BUCKET_MICROS;
+        let last = end.micros() / REVENUE_BUCKET_MICROS;
+        (first..last).map(move |bucket| RevenueKey { keyword: keyword.to_string(), bucket })
+    }
+}
+
+/// Stream as stored before payout curves, see `Aqueduct::migrate`.
+#[derive(Debug, Deserialize, Serialize, Clone, SimpleObject)]
+pub struct LegacyStream {
+    pub keywords: Vec<String>,
+    pub segments: Vec<LegacySegment>,
+    pub created: Timestamp,
+    pub milestones_received: u64,
+    pub periods_received: u64,
+}
+
+impl From<LegacyStream> for Stream {
+    fn from(legacy: LegacyStream) -> Self {
+        Stream {
+            keywords: legacy.keywords,
+            segments: legacy.segments.into_iter().map(Segment::from).collect(),
+            created: legacy.created,
+            milestones_received: legacy.milestones_received,
+            periods_received: legacy.periods_received,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum PayoutStatus {
+    /// everything owed was paid, including nothing being owed
+    Full,
+    /// the company balance only covered part of what was owed
+    Partial,
+    /// the company balance was empty
+    Failed,
+}
+
+/// What a company paid the holder of a stream token for one `Operation::Receive`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, SimpleObject)]
+pub struct Payout {
+    pub owed: Amount,   //including what earlier receives could not pay
+    pub paid: Amount,
+    pub status: PayoutStatus,
+}
+
+impl Payout {
+    pub fn new(owed: Amount, available: Amount) -> Self {
+        let paid = std::cmp::min(owed, available);
+        let status = if paid == owed {
+            PayoutStatus::Full
+        } else if paid == Amount::zero() {
+            PayoutStatus::Failed
+        } else {
+            PayoutStatus::Partial
+        };
+        Payout { owed, paid, status }
+    }
+
+    /// What is still owed after this payout.
+    pub fn shortfall(&self) -> Amount {
+        self.owed.saturating_sub(self.paid)
+    }
+}
+
+/// A listing as mirrored to the chain that minted the token.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, SimpleObject)]
+pub struct MarketListing {
+    pub seller: ChainId,
+    pub price: Amount,
+}
+
+/// The best live offer on a token, kept by the chain that minted it.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, SimpleObject)]
+pub struct Offer {
+    pub bidder: ChainId,
+    pub amount: Amount,     //escrowed on the bidder chain
+    pub expires: Timestamp,
+}
+
+/// An accepted offer or won auction on the bidder chain: the escrowed `amount` is promised
+/// to `seller` and paid once the token arrived here.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, SimpleObject)]
+pub struct Settlement {
+    pub seller: ChainId,
+    pub amount: Amount,
+}
+
+/// How a token on this chain is for sale.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum Listing {
+    FixedPrice {
+        price: Amount,
+    },
+    Auction(Auction),
+}
+scalar!(Listing);
+
+impl Listing {
+    /// Price mirrored to the minting chain's `market`, the reserve for auctions.
+    pub fn price(&self) -> Amount {
+        match self {
+            Listing::FixedPrice { price } => *price,
+            Listing::Auction(auction) => auction.reserve,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum AuctionKind {
+    /// highest bid at `end` wins
+    English,
+    /// price falls linearly from `start_price` at `start` to `reserve` at `end`, first bid
+    /// meeting it wins
+    Dutch {
+        start_price: Amount,
+    },
+}
+scalar!(AuctionKind);
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Bid {
+    pub bidder: ChainId,
+    pub amount: Amount,     //escrowed on the bidder chain
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Auction {
+    pub kind: AuctionKind,
+    pub reserve: Amount,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub best_bid: Option<Bid>,
+}
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuctionError {
+    #[error("an auction has to end after it starts")]
+    EndsBeforeStart,
+
+    #[error("a dutch auction cannot start below its reserve")]
+    StartBelowReserve,
+
+    #[error("the auction has ended")]
+    Ended,
+
+    #[error("the auction is still running")]
+    StillRunning,
+
+    #[error("the dutch auction already has a winning bid")]
+    AlreadySold,
+
+    #[error("bid below the reserve")]
+    BelowReserve,
+
+    #[error("bid below the current dutch auction price")]
+    BelowCurrentPrice,
+
+    #[error("bid does not beat the best bid")]
+    NotAboveBestBid,
+}
+
+impl Auction {
+    pub fn new(kind: AuctionKind, reserve: Amount, start: Timestamp, end: Timestamp) -> Result<Self, AuctionError> {
+        if end <= start {
+            return Err(AuctionError::EndsBeforeStart);
+        }
+        if let AuctionKind::Dutch { start_price } = kind {
+            if start_price < reserve {
+                return Err(AuctionError::StartBelowReserve);
+            }
+        }
+        Ok(Auction { kind, reserve, start, end, best_bid: None })
+    }
+
+    /// Lowest acceptable bid at `now`.
+    pub fn price_at(&self, now: Timestamp) -> Amount {
+        match self.kind {
+            AuctionKind::English => self.reserve,
+            AuctionKind::Dutch { start_price } => {
+                let elapsed = now.micros().saturating_sub(self.start.micros());
+                let duration = self.end.micros() - self.start.micros();
+                let drop = u128::from(start_price.saturating_sub(self.reserve));
+                // elapsed / duration <= 1, so neither can fail
+                let progress = fixed::ratio(elapsed.min(duration), duration).unwrap_or(fixed::ONE) as u128;
+                let decline = fixed::scale(drop, progress).unwrap_or(drop);
+                start_price.saturating_sub(Amount::from_atto(decline))
+            }
+        }
+    }
+
+    /// Records `bid` if it is acceptable at `now`, returning the bid it replaces.
+    pub fn place(&mut self, bid: Bid, now: Timestamp) -> Result<Option<Bid>, AuctionError> {
+        if now >= self.end {
+            return Err(AuctionError::Ended);
+        }
+        if bid.amount < self.reserve {
+            return Err(AuctionError::BelowReserve);
+        }
+        match (&self.kind, &self.best_bid) {
+            (AuctionKind::Dutch { .. }, Some(_)) => return Err(AuctionError::AlreadySold),
+            (AuctionKind::Dutch { .. }, None) if bid.amount < self.price_at(now) => {
+                return Err(AuctionError::BelowCurrentPrice)
+            }
+            (AuctionKind::English, Some(best)) if bid.amount <= best.amount => {
+                return Err(AuctionError::NotAboveBestBid)
+            }
+            _ => {}
+        }
+        Ok(self.best_bid.replace(bid))
+    }
+
+    /// The winning bid, once the auction can be closed at `now`.
+    pub fn close(&self, now: Timestamp) -> Result<Option<Bid>, AuctionError> {
+        match (&self.kind, &self.best_bid) {
+            (AuctionKind::Dutch { .. }, Some(bid)) => Ok(Some(bid.clone())),
+            _ if now < self.end => Err(AuctionError::StillRunning),
+            (_, best_bid) => Ok(best_bid.clone()),
+        }
+    }
+}
+
+/// 100% in basis points, the most a royalty can take.
+pub const MAX_ROYALTY_BPS: u16 = 10_000;
+
+/// Share of `price` a royalty of `bps` basis points takes, rounded down.
+pub fn royalty_share(price: Amount, bps: u16) -> Amount {
+    let bps = u128::from(bps.min(MAX_ROYALTY_BPS));
+    let price = u128::from(price);
+    // split so price * bps cannot overflow
+    Amount::from_atto(price / 10_000 * bps + price % 10_000 * bps / 10_000)
+}
+
+/// `balance` could not cover a debit or withdrawal.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("insufficient balance: {requested} requested, {available} available")]
+pub struct InsufficientBalance {
+    pub requested: Amount,
+    pub available: Amount,
+}
+
+/// `balance` once `amount` is taken out of it.
+pub fn checked_debit(balance: Amount, amount: Amount) -> Result<Amount, InsufficientBalance> {
+    let mut rest = balance;
+    rest.try_sub_assign(amount)
+        .map_err(|_| InsufficientBalance { requested: amount, available: balance })?;
+    Ok(rest)
+}
+
+/// `balance` once a native `Operation::Deposit` of `amount` is booked. Native tokens never
+/// leave the chain balance, so only what `balance` does not book yet can be deposited.
+pub fn native_deposit(balance: Amount, chain_balance: Amount, amount: Amount) -> Result<Amount, InsufficientBalance> {
+    checked_debit(chain_balance.saturating_sub(balance), amount)?;
+    let mut total = balance;
+    total.saturating_add_assign(amount);
+    Ok(total)
+}
+
+/// Where `Operation::Withdraw` sends tokens. Native tokens can only be withdrawn on the
+/// chain itself; fungible tokens go to `owner` on `chain_id`, the signer if omitted.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Beneficiary {
+    pub chain_id: ChainId,
+    pub owner: Option<Owner>,
+}
+scalar!(Beneficiary);
+
+/// What an allowed `Operation::Withdraw` does, see `plan_withdrawal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Withdrawal {
+    /// the fungible token application sends the amount to `owner` on the beneficiary chain
+    Fungible {
+        owner: Owner,
+        rest: Amount,
+    },
+    /// the amount stops being booked and is the chain owner's again. A contract cannot
+    /// move native tokens, the owner does that with a system transfer
+    Native {
+        rest: Amount,
+    },
+}
+
+impl Withdrawal {
+    /// `balance` once the withdrawal is booked.
+    pub fn rest(&self) -> Amount {
+        match self {
+            Withdrawal::Fungible { rest, .. } | Withdrawal::Native { rest } => *rest,
+        }
+    }
+}
+
+/// Why an `Operation::Withdraw` was rejected.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalError {
+    #[error(transparent)]
+    InsufficientBalance(#[from] InsufficientBalance),
+
+    #[error("native tokens can only be withdrawn on the chain holding them")]
+    NativeToOtherChain,
+
+    #[error("fungible withdrawals need an owner, none given and the operation is unsigned")]
+    NoOwner,
+}
+
+/// Checks a withdrawal of `amount` out of `balance` on `chain_id` for `signer`, `fungible`
+/// telling whether balances are settled with a fungible token application.
+pub fn plan_withdrawal(
+    balance: Amount,
+    amount: Amount,
+    to: &Beneficiary,
+    signer: Option<Owner>,
+    chain_id: ChainId,
+    fungible: bool,
+) -> Result<Withdrawal, WithdrawalError> {
+    let rest = checked_debit(balance, amount)?;
+    if fungible {
+        let owner = to.owner.or(signer).ok_or(WithdrawalError::NoOwner)?;
+        Ok(Withdrawal::Fungible { owner, rest })
+    } else if to.chain_id != chain_id {
+        Err(WithdrawalError::NativeToOtherChain)
+    } else {
+        Ok(Withdrawal::Native { rest })
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Operation {
     Mint {
@@ -93,6 +858,7 @@ pub enum Operation {
         description: String,
         keywords: Vec<String>,
         segments: Vec<Segment>,
+        royalty_bps: Option<u16>,   //share of every resale paid to the company, in basis points
     },
     Burn {
         token_id: TokenId,
@@ -114,6 +880,68 @@ pub enum Operation {
         amount: Amount,
         keyword: String,
     },
+    Deposit {
+        amount: Amount,
+    },
+    Withdraw {
+        amount: Amount,
+        to: Beneficiary,
+    },
+    RetireStream {
+        stream_id: StreamId,
+    },
+    MakeOffer {
+        token_id: TokenId,
+        amount: Amount,
+        expires: Timestamp,
+    },
+    AcceptOffer {
+        token_id: TokenId,
+        amount: Amount,     //the offer being accepted, a different one is not settled in its place
+        bidder: ChainId,
+    },
+    WithdrawOffer {
+        token_id: TokenId,
+        amount: Amount,     //the offer being withdrawn, refunded out of its escrow
+    },
+    ListAuction {
+        token_id: TokenId,
+        kind: AuctionKind,
+        reserve: Amount,
+        end: Timestamp,
+    },
+    Bid {
+        token_id: TokenId,
+        seller: ChainId,
+        amount: Amount,
+    },
+    CloseAuction {
+        token_id: TokenId,
+        seller: ChainId,
+    },
+    Approve {
+        token_id: TokenId,
+        spender: Option<ChainId>,   //None clears the approval
+    },
+    SetApprovalForAll {
+        operator: ChainId,
+        approved: bool,
+    },
+    SafeTransferFrom {
+        from: ChainId,
+        to: ChainId,
+        token_id: TokenId,
+    },
+    Transfer {
+        token_id: TokenId,
+        to: ChainId,
+    },
+    RegisterReceiver {
+        application_id: Option<ApplicationId>,  //None accepts every token again
+    },
+    RecoverFlow {
+        flow_id: FlowId,
+    },
 }
 
 
@@ -126,10 +954,177 @@ pub struct M4 {
     pub original_ope_context: Option<OperationContext>,
     pub original_message: Option<ActualMessage>,
     pub original_msg_context: Option<MessageContext>,
+    pub flow_id: FlowId,
+    pub step: u64,      //counts every message of the flow, 0 where it started
+}
+
+impl M4 {
+    /// The same flow one step further, for the message a step sends on.
+    pub fn next(&self) -> M4 {
+        let mut m4 = self.clone();
+        m4.step += 1;
+        m4
+    }
 }
 
 //m4 M4 end
 
+/// A cross-chain flow, numbered by the chain it started on.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct FlowId {
+    pub chain_id: ChainId,
+    pub index: u64,
+}
+
+scalar!(FlowId);
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum FlowKind {
+    Buy,
+    Burn,
+    Receive,
+    SafeTransferFrom,
+}
+
+/// Last step kept for a flow recovered on a chain, none of its steps runs there again.
+pub const FENCED_STEP: u64 = u64::MAX;
+
+/// What a chain does with a step of a flow, given the last step of it the chain handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepCheck {
+    Fresh,
+    /// delivered again, or behind a later step that was handled already
+    Replayed,
+    /// the flow was recovered here
+    Fenced,
+}
+
+pub fn classify_step(last: Option<u64>, step: u64) -> StepCheck {
+    match last {
+        Some(FENCED_STEP) => StepCheck::Fenced,
+        Some(last) if step <= last => StepCheck::Replayed,
+        _ => StepCheck::Fresh,
+    }
+}
+
+/// How long a flow may stay pending before `Operation::RecoverFlow` can undo it, one day in
+/// microseconds.
+pub const FLOW_TIMEOUT_MICROS: u64 = 24 * 3_600_000_000;
+
+/// Something a flow did on a chain, undone there by `Operation::RecoverFlow`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum Compensation {
+    /// refund `amount` escrowed for `token_id`
+    RefundEscrow { token_id: TokenId, amount: Amount },
+    /// take `token` back, once its minting chain confirms it never got a new owner
+    RestoreToken { token: Token },
+    /// take `token` back from `recipient` it was sent to, unless the flow finished there
+    RestoreSentToken { token: Token, recipient: ChainId },
+}
+
+scalar!(Compensation);
+
+/// A flow as seen by the chain it started on, or by a chain that gave a token up in it. Either
+/// only hears of the steps run on it.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, SimpleObject)]
+pub struct FlowState {
+    pub id: FlowId,
+    pub kind: FlowKind,
+    pub step: String,       //last message of the flow sent or handled here
+    pub started: Timestamp,
+    pub updated: Timestamp,
+    pub deadline: Timestamp,
+    pub outcome: Option<FlowOutcome>,   //None while the flow is pending
+    pub compensations: Vec<Compensation>,   //in the order they were recorded
+}
+
+impl FlowState {
+    pub fn new(id: FlowId, kind: FlowKind, step: &str, now: Timestamp) -> Self {
+        FlowState {
+            id,
+            kind,
+            step: step.to_string(),
+            started: now,
+            updated: now,
+            deadline: Timestamp::from(now.micros().saturating_add(FLOW_TIMEOUT_MICROS)),
+            outcome: None,
+            compensations: Vec::new(),
+        }
+    }
+
+    /// Whether `Operation::RecoverFlow` may run at `now`: still pending past its deadline.
+    pub fn recoverable(&self, now: Timestamp) -> bool {
+        self.outcome.is_none() && now >= self.deadline
+    }
+}
+
+/// Why a step of a cross-chain flow failed, as reported back to the chain that started it.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum ErrorCode {
+    Bcs,
+    Json,
+    View,
+    TokenNotListed,
+    TokenIsAuctioned,
+    /// the seller's listing no longer asks the price the buyer escrowed
+    PriceChanged,
+    InsufficientBalance,
+    /// the escrow a payment was to come out of is gone
+    NoEscrow,
+    NoOwnerInMinted,
+    NotOwner,
+    NotAnOperatorNorApproved,
+    /// the recipient did not acknowledge the token
+    Refused,
+    /// the flow stayed pending past its deadline and was recovered
+    TimedOut,
+    /// the flow was recovered or had ended already
+    FlowNotPending,
+    CompanyDoesntHaveStream,
+    FixedPoint,
+    MismatchedOperation,
+    MismatchedOperationContext,
+    MismatchedMessage,
+    MismatchedMessageContext,
+    /// any error a flow is not expected to run into
+    Other,
+}
+
+/// What a flow that went through hands back besides succeeding.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum FlowPayload {
+    Done,
+    /// `Operation::Buy`, `royalty` being the part of `price` that went to the minting chain, or
+    /// a `SafeTransferFrom` paying for an accepted offer or a won auction
+    Bought { price: Amount, royalty: Amount },
+    /// `Operation::Receive`, what the company paid for the token
+    Payout(Payout),
+}
+
+/// How a cross-chain flow ended, carried by `Message::Result`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum FlowOutcome {
+    Ok(FlowPayload),
+    Err(ErrorCode),
+    /// the flow failed with the first code and undoing it failed with the second
+    RollbackFailed(ErrorCode, ErrorCode),
+}
+scalar!(FlowOutcome);
+
+impl FlowOutcome {
+    pub fn is_err(&self) -> bool {
+        !matches!(self, FlowOutcome::Ok(_))
+    }
+
+    /// The code the flow failed with, if it did.
+    pub fn error(&self) -> Option<ErrorCode> {
+        match self {
+            FlowOutcome::Ok(_) => None,
+            FlowOutcome::Err(code) | FlowOutcome::RollbackFailed(code, _) => Some(*code),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub enum Message {
     #[default]
@@ -140,11 +1135,71 @@ pub enum Message {
         token_id: TokenId,
         data: Vec<u8>,
     },
+    MirrorListing {
+        token_id: TokenId,
+        price: Option<Amount>,  //None once the listing is cancelled
+    },
+    PlaceOffer {
+        token_id: TokenId,
+        amount: Amount,
+        expires: Timestamp,
+    },
+    WithdrawOffer {
+        token_id: TokenId,
+        amount: Amount,
+    },
+    AcceptOffer {
+        token_id: TokenId,
+        amount: Amount,
+        bidder: ChainId,
+    },
+    SettleEscrow {
+        token_id: TokenId,
+        seller: ChainId,
+        amount: Amount,
+    },
+    EscrowPayment {
+        token_id: TokenId,
+        buyer: ChainId,
+        amount: Amount,
+    },
+    RefundEscrow {
+        token_id: TokenId,
+        amount: Amount,
+    },
+    PlaceBid {
+        token_id: TokenId,
+        amount: Amount,
+    },
+    CloseAuction {
+        token_id: TokenId,
+    },
+    Royalty {
+        token_id: TokenId,
+        amount: Amount,
+    },
+    RecoverToken {
+        flow_id: FlowId,
+        token: Token,
+    },
+    TokenRecovered {
+        token: Token,
+    },
+    ReturnToken {
+        flow_id: FlowId,
+        token: Token,
+    },
+    RecoverReturnedToken {
+        flow_id: FlowId,
+        token: Token,
+        owner: ChainId,     //the chain that recovered the flow, having given the token up
+        returned: bool,     //whether the recipient gave the token up
+    },
 //m4 Message start
     Result {
         m4: M4,
         origin: ChainId,
-        result: String,
+        result: FlowOutcome,
     },
     Buy_GetOwner {
         m4: M4,
@@ -152,27 +1207,61 @@ pub enum Message {
     Buy_GetPrice {
         m4: M4,
         cur_owner: ChainId,
+        royalty_bps: u16,
     },
     Buy_BackToPay {
         m4: M4,
         cur_owner: ChainId,
         price: Amount,
+        royalty_bps: u16,
+    },
+    Buy_TransferFrom {
+        m4: M4,
+        cur_owner: ChainId,
+        price: Amount,
+        royalty_bps: u16,
+    },
+    Buy_UpdateMintedChain {
+        m4: M4,
+        cur_owner: ChainId,
+        price: Amount,
+        royalty_bps: u16,
+        token: Token,
+    },
+    Buy_TransferTo {
+        m4: M4,
+        cur_owner: ChainId,
+        price: Amount,
+        royalty_bps: u16,
+        token: Token,
     },
     Buy_ReceivePayment {
         m4: M4,
         cur_owner: ChainId,
         price: Amount,
+        royalty_bps: u16,
     },
-    Buy_ReceiveRollbackPayment {
+    Buy_ReceiveRollbackPayment {  //only sent before Buy escrowed the payment
         m4: M4,
         cur_owner: ChainId,
         price: Amount,
+        royalty_bps: u16,
+    },
+    Burn_Minted {
+        m4: M4,
+        metadata: TokenMetadata,
     },
     Receive_Company {
         m4: M4,
         metadata: TokenMetadata,
         stream_id: StreamId,
     },
+    Receive_Credit {
+        m4: M4,
+        metadata: TokenMetadata,
+        stream_id: StreamId,
+        payout: Payout,
+    },
     SafeTransferFrom_CheckOwn {
         m4: M4,
         sender: ChainId,
@@ -200,19 +1289,79 @@ pub enum Message {
         own: bool,
         token: Token,
     },
-    Result_BackToPay {
+//m4 Message end
+    //rollbacks of the flows above, written by hand after generating m4
+    Buy_RollbackMinted {
         m4: M4,
-        from: ChainId,
-        to: ChainId,
+        cur_owner: ChainId,
         price: Amount,
+        token: Token,
+        error: ErrorCode,
     },
-    Result_ReceivePayment {
+    Buy_RollbackOwner {
         m4: M4,
-        from: ChainId,
-        to: ChainId,
         price: Amount,
+        token: Token,
+        error: ErrorCode,
     },
-//m4 Message end
+    Buy_RefundEscrow {
+        m4: M4,
+        price: Amount,
+        error: ErrorCode,
+    },
+    Burn_RollbackOwner {
+        m4: M4,
+        metadata: TokenMetadata,
+        error: ErrorCode,
+    },
+    SafeTransferFrom_RollbackMinted {
+        m4: M4,
+        token: Token,
+        error: ErrorCode,
+    },
+    SafeTransferFrom_RollbackOwner {
+        m4: M4,
+        token: Token,
+        error: ErrorCode,
+    },
+    SafeTransferFrom_RollbackTo {
+        m4: M4,
+        token: Token,
+        error: ErrorCode,
+    },
+}
+
+impl Message {
+    /// The flow a message belongs to and the step it is, `None` for plain messages.
+    pub fn flow(&self) -> Option<(&M4, &'static str)> {
+        match self {
+            Message::Result { m4, .. } => Some((m4, "Result")),
+            Message::Buy_GetOwner { m4, .. } => Some((m4, "Buy_GetOwner")),
+            Message::Buy_GetPrice { m4, .. } => Some((m4, "Buy_GetPrice")),
+            Message::Buy_BackToPay { m4, .. } => Some((m4, "Buy_BackToPay")),
+            Message::Buy_TransferFrom { m4, .. } => Some((m4, "Buy_TransferFrom")),
+            Message::Buy_UpdateMintedChain { m4, .. } => Some((m4, "Buy_UpdateMintedChain")),
+            Message::Buy_TransferTo { m4, .. } => Some((m4, "Buy_TransferTo")),
+            Message::Buy_ReceivePayment { m4, .. } => Some((m4, "Buy_ReceivePayment")),
+            Message::Buy_ReceiveRollbackPayment { m4, .. } => Some((m4, "Buy_ReceiveRollbackPayment")),
+            Message::Buy_RollbackMinted { m4, .. } => Some((m4, "Buy_RollbackMinted")),
+            Message::Buy_RollbackOwner { m4, .. } => Some((m4, "Buy_RollbackOwner")),
+            Message::Buy_RefundEscrow { m4, .. } => Some((m4, "Buy_RefundEscrow")),
+            Message::Burn_Minted { m4, .. } => Some((m4, "Burn_Minted")),
+            Message::Burn_RollbackOwner { m4, .. } => Some((m4, "Burn_RollbackOwner")),
+            Message::SafeTransferFrom_RollbackMinted { m4, .. } => Some((m4, "SafeTransferFrom_RollbackMinted")),
+            Message::SafeTransferFrom_RollbackOwner { m4, .. } => Some((m4, "SafeTransferFrom_RollbackOwner")),
+            Message::SafeTransferFrom_RollbackTo { m4, .. } => Some((m4, "SafeTransferFrom_RollbackTo")),
+            Message::Receive_Company { m4, .. } => Some((m4, "Receive_Company")),
+            Message::Receive_Credit { m4, .. } => Some((m4, "Receive_Credit")),
+            Message::SafeTransferFrom_CheckOwn { m4, .. } => Some((m4, "SafeTransferFrom_CheckOwn")),
+            Message::SafeTransferFrom_TransferFrom { m4, .. } => Some((m4, "SafeTransferFrom_TransferFrom")),
+            Message::SafeTransferFrom_TransferTo { m4, .. } => Some((m4, "SafeTransferFrom_TransferTo")),
+            Message::SafeTransferFrom_UpdateMintedChain { m4, .. } => Some((m4, "SafeTransferFrom_UpdateMintedChain")),
+            Message::SafeTransferFrom_OnERC721Received { m4, .. } => Some((m4, "SafeTransferFrom_OnERC721Received")),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -225,6 +1374,6 @@ pub enum ActualMessage{
     },
     Result {
         origin: ChainId,
-        result: String,
+        result: FlowOutcome,
     },
 }
@@ -2,24 +2,31 @@ match operation {
     Operation::Buy {
         token_id,
     } => {
-        let cur_owner: ChainId = #[message(GetOwner)](token_id.minted_chain, self.get_owner(token_id).await);
+        let cur_owner: ChainId = #[message(GetOwner)](token_id.minted_chain, self.get_owner(token_id.clone()).await);
+        let royalty_bps: u16 = self.royalties.get(&token_id).await?.unwrap_or(0);
         let price: Amount = #[message(GetPrice)](cur_owner, self.get_price(token_id).await);
-        #[message(BackToPay)](m4.original_chain, self.debit(price).await);
+        #[message(BackToPay)](m4.original_chain, self.escrow_payment(&m4, &token_id, price).await);
+        let token: Token = #[message(TransferFrom)](cur_owner, self.sell(&m4, token_id.clone(), price).await);
+        #[message(UpdateMintedChain)](token_id.minted_chain, self.transfer_update_minted(token_id.clone(), m4.original_chain, cur_owner).await);
+        #[message(TransferTo)](m4.original_chain, self.receive_bought(&m4, &token, cur_owner, price).await);
         #[message(ReceivePayment)](cur_owner, self.credit(price).await);
-        if let Ok(data) = to_bytes(&price) {
-            let message: Message = Message::SafeTransferFrom {
-                from: cur_owner, 
-                to: m4.original_chain,
-                token_id,
-                data,
-            };
-            //rollback moni if fail (implemented by hand after generating m4)
-            return Ok(ExecutionResult::default().with_authenticated_message(cur_owner, message));
-        } else {
-            self.debit(price).await;
-            #[message(ReceiveRollbackPayment)](cur_owner, self.credit(price).await);
-            return Err(Error::BcsPriceError);
-        }
+        //royalty to the minting chain, reported in the result (implemented by hand after generating m4),
+        //zero when it could not be paid so the finished sale is not held up
+        let royalty: Amount = self.pay_royalty(&token_id, price, royalty_bps).await;
+        //rollback by hand after generating m4: a failed leg sends RollbackMinted, RollbackOwner
+        //(relisting the token) and RefundEscrow, starting from the first leg that has to be undone.
+        //RefundEscrow runs refund_payment, which skips escrow Operation::RecoverFlow refunded
+        return Ok(ExecutionResult::default());
+    },
+    Operation::Burn {
+        token_id,
+    } => {
+        let metadata: TokenMetadata = self.burn(token_id.clone()).await?;
+        self.record_compensation(&m4, FlowKind::Burn, Compensation::RestoreToken { token: Token { id: token_id.clone(), metadata: metadata.clone() } }).await?;
+        #[message(Minted)](token_id.minted_chain, self.clear_burned(token_id, &metadata, m4.original_chain).await);
+        //rollback by hand after generating m4: when the minting chain refuses, RollbackOwner
+        //gives the token back to the burner
+        return Ok(ExecutionResult::default());
     },
     Operation::Receive {
         token_id,
@@ -27,7 +34,8 @@ match operation {
         let metadata: TokenMetadata = self.get_token(token_id).await?;
         if let Ok(stream_id) = serde_json::from_str::<StreamId>(&metadata.image) {
             #[addvar(stream_id: StreamId)]
-            #[message(Company)](stream_id.company.clone(), self.handle_receive(stream_id, m4.original_chain).await);
+            let payout: Payout = #[message(Company)](stream_id.company.clone(), self.pay_out(stream_id, m4.original_chain).await);
+            #[message(Credit)](m4.original_chain, self.receive_payout(token_id, payout).await);
             return Ok(ExecutionResult::default());
         } else {
             return Err(Error::JsonError);
@@ -36,6 +44,7 @@ match operation {
 }
 
 match message {
+    //Operation::Transfer sends SafeTransferFrom with empty data by hand after generating m4
     Message::SafeTransferFrom {
         from,
         to,
@@ -43,58 +52,35 @@ match message {
         data,
     } => {
         let sender: ChainId = system_api::current_chain_id();
-        if from != sender {
-            return Err(Error::NotAnOperatorNorApproved);
-        }
-        let own: bool = #[message(CheckOwn)](from, self.check_own(token_id).await);
+        let own: bool = #[message(CheckOwn)](from, self.check_own_or_approved(token_id, sender).await);
         if !own { return Err(Error::DoesNotOwnToken); }
         // throws if to is zero address
         // throws if tokenid is not a valid nft
         let token: Token = #[message(TransferFrom)](from, self.transfer_from_me(token_id).await);
+        //by hand after generating m4: recorded as Compensation::RestoreSentToken when `from` started the flow
         #[message(TransferTo)](to, self.transfer_to_me(&token).await);
-        #[message(UpdateMintedChain)](token_id.minted_chain, self.transfer_update_minted(token_id, to).await);
-        // rollback if something goes wrong or check fails?
-        let check: Vec<u8> = #[message(OnERC721Received)](to, self.on_erc721_received(OnERC721Received {
+        #[message(UpdateMintedChain)](token_id.minted_chain, self.transfer_update_minted(token_id, to, from).await);
+        //by hand after generating m4: accept_transfer also pays an accepted offer or won auction
+        //out of the buyer's escrow, the step then ends with FlowPayload::Bought
+        //by hand after generating m4: an accepting `to` fences the flow unless it started it, so
+        //Operation::RecoverFlow no longer takes the token back
+        #[message(OnERC721Received)](to, self.accept_transfer(OnERC721Received {
             from: from.clone(), 
             to: to.clone(),
             token_id: token_id.clone(),
             data: data.clone(),
         }).await);
-        if let Ok(b) = to_bytes(&OnERC721Received {
-            from: from.clone(), 
-            to: to.clone(),
-            token_id: token_id.clone(),
-            data: data.clone(),
-        }) {
-            let mut bb = b;
-            keccak256(&mut bb);
-            if check != bb { return Err(Error::AfterTransferCheckFailed); }
-            return Ok(ExecutionResult::default());
-        } else {
-            return Err(Error::BcsError);
-        }
+        //rollback by hand after generating m4: a failing leg undoes the legs before it and ends
+        //in rollback_safe_transfer, which sends RollbackOwner to `from`. A failed TransferTo goes
+        //there directly, a failed UpdateMintedChain through RollbackTo (the token leaves `to`),
+        //a refusal takes the token back from `to` and sends RollbackMinted first
+        return Ok(ExecutionResult::default());
     },
     Message::Result { m4, origin, result } => {
         info!("m4: {:?} origin: {} res: {}", m4, origin, result);
-        if let Some(ActualMessage::SafeTransferFrom {
-                from,
-                to,
-                token_id,
-                data,
-            }) = m4.original_message {
-            #[addvar(from: ChainId)]
-            #[addvar(to: ChainId)]
-            if result.contains("Err") {
-                if let Ok(price) = from_bytes::<Amount>(&data) {
-                    #[addvar(price: Amount)]
-                    #[message(BackToPay)](from, self.debit(price).await);
-                    #[message(ReceivePayment)](to, self.credit(price).await);
-                    return Ok(ExecutionResult::default());
-                } else {
-                    return Err(Error::BcsError);
-                }
-            }
-        }
-        return Ok(ExecutionResult::default())
+        //by hand after generating m4: finish_flow, then end_settlement credits the seller or sends
+        //RefundEscrow to `to` when `data` carries a price
+        self.finish_flow(&m4, result.clone()).await?;
+        return self.end_settlement(&m4, &result).await;
     }
 }
@@ -0,0 +1,226 @@
+//! Offers on tokens, listed or not. The best live offer per token is kept on the chain that
+//! minted it, the offered amount stays escrowed on the bidder chain until it is refunded, or
+//! until the offer is accepted and the token arrived there through `SafeTransferFrom`.
+
+use crate::{state::Aqueduct, Error};
+use aqueduct::{checked_debit, ActualMessage, FlowOutcome, FlowPayload, Message, Offer, Settlement, TokenId, M4};
+use bcs::{from_bytes, to_bytes};
+use linera_sdk::{
+    base::{Amount, ChainId, Timestamp},
+    contract::system_api,
+    ExecutionResult,
+};
+use log::info;
+
+impl Aqueduct {
+    /// Bidder side of `Operation::MakeOffer`.
+    pub(crate) async fn make_offer(
+        &mut self,
+        token_id: TokenId,
+        amount: Amount,
+        expires: Timestamp,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        if expires <= system_api::current_system_time() {
+            return Err(Error::OfferExpiredError);
+        }
+        self.escrow_add(&token_id, amount).await?;
+        let message = Message::PlaceOffer { token_id: token_id.clone(), amount, expires };
+        Ok(ExecutionResult::default().with_authenticated_message(token_id.minted_chain, message))
+    }
+
+    /// Minting chain side of `Operation::MakeOffer`: keeps the offer if it beats the live one,
+    /// refunding whichever offer lost.
+    pub(crate) async fn place_offer(
+        &mut self,
+        token_id: TokenId,
+        offer: Offer,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        let now = system_api::current_system_time();
+        let refunded = match self.offers.get(&token_id).await? {
+            Some(live) if live.expires > now && live.bidder != offer.bidder && live.amount >= offer.amount => {
+                return Ok(Self::refund(token_id, offer));
+            }
+            live => live,
+        };
+        if offer.expires <= now {
+            return Ok(Self::refund(token_id, offer));
+        }
+        self.offers.insert(&token_id, offer)?;
+        Ok(match refunded {
+            Some(refunded) => Self::refund(token_id, refunded),
+            None => ExecutionResult::default(),
+        })
+    }
+
+    /// Minting chain side of `Operation::WithdrawOffer`: refunds the live offer as long as it
+    /// is still the one of `amount` the bidder withdrew.
+    pub(crate) async fn withdraw_offer(
+        &mut self,
+        token_id: TokenId,
+        amount: Amount,
+        bidder: ChainId,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        match self.offers.get(&token_id).await? {
+            Some(offer) if offer.bidder == bidder && offer.amount == amount => {
+                self.offers.remove(&token_id)?;
+                Ok(Self::refund(token_id, offer))
+            }
+            _ => {
+                info!("no offer of {} from {} on {:?} to withdraw", amount, bidder, token_id);
+                Ok(ExecutionResult::default())
+            }
+        }
+    }
+
+    /// Minting chain side of `Operation::AcceptOffer`: asks the bidder chain to settle with
+    /// `seller`, as long as the live offer is still the one `seller` accepted. An expired offer
+    /// is refunded instead.
+    pub(crate) async fn accept_offer(
+        &mut self,
+        token_id: TokenId,
+        seller: ChainId,
+        amount: Amount,
+        bidder: ChainId,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        if self.current_owner_minted.get(&token_id).await? != Some(seller) {
+            info!("{} does not own {:?}, offer not accepted", seller, token_id);
+            return Ok(ExecutionResult::default());
+        }
+        let offer = match self.offers.get(&token_id).await? {
+            Some(offer) if offer.bidder == bidder && offer.amount == amount => offer,
+            _ => {
+                info!("no offer of {} from {} on {:?} to accept", amount, bidder, token_id);
+                return Ok(ExecutionResult::default());
+            }
+        };
+        self.offers.remove(&token_id)?;
+        if offer.expires <= system_api::current_system_time() {
+            return Ok(Self::refund(token_id, offer));
+        }
+        let message = Message::SettleEscrow { token_id, seller, amount: offer.amount };
+        Ok(ExecutionResult::default().with_authenticated_message(offer.bidder, message))
+    }
+
+    /// Bidder side of an accepted offer or a won auction: promises the escrow to `seller`,
+    /// who then sends the token. Nothing is paid before it arrives.
+    pub(crate) async fn settle_escrow(
+        &mut self,
+        token_id: TokenId,
+        seller: ChainId,
+        amount: Amount,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        let escrow = self.escrow.get(&token_id).await?.unwrap_or_else(Amount::zero);
+        checked_debit(escrow, amount).map_err(|_| Error::NoEscrowForOfferError)?;
+        self.settlements.insert(&token_id, Settlement { seller, amount })?;
+        let message = Message::EscrowPayment { token_id, buyer: system_api::current_chain_id(), amount };
+        Ok(ExecutionResult::default().with_authenticated_message(seller, message))
+    }
+
+    /// Seller side of an accepted offer or a won auction: hands the token over through
+    /// `SafeTransferFrom`, the price riding along in `data`. The buyer pays once the token
+    /// arrived, a failed transfer releases the buyer's escrow.
+    pub(crate) async fn receive_escrow_payment(
+        &mut self,
+        token_id: TokenId,
+        buyer: ChainId,
+        amount: Amount,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        let seller = system_api::current_chain_id();
+        let message = Message::SafeTransferFrom {
+            from: seller,
+            to: buyer,
+            token_id,
+            data: to_bytes(&amount)?,
+        };
+        Ok(ExecutionResult::default().with_authenticated_message(seller, message))
+    }
+
+    /// Buyer side, once a token sent with `SafeTransferFrom` was accepted here: pays `from` out
+    /// of escrow if the transfer settles an accepted offer or won auction, `Done` otherwise.
+    pub(crate) async fn pay_settlement(
+        &mut self,
+        token_id: &TokenId,
+        from: ChainId,
+        data: &[u8],
+    ) -> Result<FlowPayload, Error> {
+        let amount = match (self.settlements.get(token_id).await?, from_bytes::<Amount>(data)) {
+            (Some(settlement), Ok(amount)) if settlement == (Settlement { seller: from, amount }) => amount,
+            _ => return Ok(FlowPayload::Done),
+        };
+        self.release_escrow(token_id, amount).await?;
+        self.credit(amount).await?;
+        if let Err(error) = self.debit(amount, from).await {
+            self.escrow_add(token_id, amount).await?;
+            return Err(error);
+        }
+        self.settlements.remove(token_id)?;
+        Ok(FlowPayload::Bought { price: amount, royalty: Amount::zero() })
+    }
+
+    /// Seller side, once a `SafeTransferFrom` with a price in `data` ended: books what the
+    /// buyer paid, or has the buyer's escrow released if the token did not stay there.
+    pub(crate) async fn end_settlement(&mut self, m4: &M4, result: &FlowOutcome) -> Result<ExecutionResult<Message>, Error> {
+        let (to, token_id, amount) = match m4.original_message.clone() {
+            Some(ActualMessage::SafeTransferFrom { to, token_id, data, .. }) => match from_bytes::<Amount>(&data) {
+                Ok(amount) => (to, token_id, amount),
+                Err(_) => return Ok(ExecutionResult::default()),
+            },
+            _ => return Ok(ExecutionResult::default()),
+        };
+        match result {
+            FlowOutcome::Ok(FlowPayload::Bought { price, .. }) => {
+                self.credit(*price).await?;
+                Ok(ExecutionResult::default())
+            }
+            FlowOutcome::Ok(_) => {
+                info!("{} kept {:?} without settling {}", to, token_id, amount);
+                Ok(ExecutionResult::default())
+            }
+            FlowOutcome::Err(_) | FlowOutcome::RollbackFailed(..) => {
+                let message = Message::RefundEscrow { token_id, amount };
+                Ok(ExecutionResult::default().with_authenticated_message(to, message))
+            }
+        }
+    }
+
+    /// Bidder side of a withdrawn, expired, outbid or rejected offer or bid.
+    pub(crate) async fn refund_escrow(&mut self, token_id: TokenId, amount: Amount) -> Result<(), Error> {
+        self.release_escrow(&token_id, amount).await?;
+        self.credit(amount).await
+    }
+
+    /// Bidder side of a settlement whose token never arrived, before its escrow is refunded.
+    pub(crate) async fn drop_settlement(&mut self, token_id: &TokenId, seller: ChainId, amount: Amount) -> Result<(), Error> {
+        if self.settlements.get(token_id).await? == Some(Settlement { seller, amount }) {
+            self.settlements.remove(token_id)?;
+        }
+        Ok(())
+    }
+
+    /// Moves `amount` out of the balance into the escrow held for `token_id`.
+    pub(crate) async fn escrow_add(&mut self, token_id: &TokenId, amount: Amount) -> Result<(), Error> {
+        let balance = checked_debit(self.balance().await, amount)
+            .map_err(|_| Error::InsufficientBalanceError)?;
+        self.balance.set(balance);
+        let mut escrow = self.escrow.get(token_id).await?.unwrap_or_else(Amount::zero);
+        escrow.saturating_add_assign(amount);
+        self.escrow.insert(token_id, escrow)?;
+        Ok(())
+    }
+
+    pub(crate) async fn release_escrow(&mut self, token_id: &TokenId, amount: Amount) -> Result<(), Error> {
+        let escrow = self.escrow.get(token_id).await?.unwrap_or_else(Amount::zero);
+        let rest = checked_debit(escrow, amount).map_err(|_| Error::NoEscrowForOfferError)?;
+        if rest == Amount::zero() {
+            self.escrow.remove(token_id)?;
+        } else {
+            self.escrow.insert(token_id, rest)?;
+        }
+        Ok(())
+    }
+
+    fn refund(token_id: TokenId, offer: Offer) -> ExecutionResult<Message> {
+        let message = Message::RefundEscrow { token_id, amount: offer.amount };
+        ExecutionResult::default().with_authenticated_message(offer.bidder, message)
+    }
+}
@@ -0,0 +1,153 @@
+//! Flows stuck on a chain that never handles its step. Past the deadline of a flow, the chain
+//! it started on, and any chain that gave a token up in it, can undo what the flow did there
+//! with `Operation::RecoverFlow`: escrow is refunded at once, a token is only taken back once
+//! its minting chain confirms nobody else became its owner, and the minting chain then drops
+//! any step of the flow still in flight. A token sent to another chain is first given up by
+//! that chain, which drops the flow's later steps too, so it never ends up owned on both chains.
+
+use crate::{state::Aqueduct, Error};
+use aqueduct::{Compensation, ErrorCode, FlowId, FlowKind, FlowOutcome, FlowState, Message, Token, FENCED_STEP, M4};
+use linera_sdk::{base::ChainId, contract::system_api, ExecutionResult};
+use log::info;
+
+impl Aqueduct {
+    /// Remembers how to undo a step that ran here. A chain the flow did not start on keeps an
+    /// entry of its own for it, its step being the message it sends on (see `record_sent`).
+    pub(crate) async fn record_compensation(&mut self, m4: &M4, kind: FlowKind, compensation: Compensation) -> Result<(), Error> {
+        let mut flow = match self.flows.get(&m4.flow_id).await? {
+            Some(flow) => flow,
+            None if m4.original_chain != system_api::current_chain_id() => {
+                self.pending_flow_ids.insert(&m4.flow_id)?;
+                FlowState::new(m4.flow_id.clone(), kind, "", system_api::current_system_time())
+            }
+            None => return Ok(()),
+        };
+        flow.compensations.push(compensation);
+        self.flows.insert(&m4.flow_id, flow)?;
+        Ok(())
+    }
+
+    /// Forgets the compensations of a flow whose steps here are settled for good.
+    pub(crate) async fn drop_compensations(&mut self, m4: &M4) -> Result<(), Error> {
+        if let Some(mut flow) = self.flows.get(&m4.flow_id).await? {
+            flow.compensations.clear();
+            self.flows.insert(&m4.flow_id, flow)?;
+        }
+        Ok(())
+    }
+
+    /// `FlowRecoveredError` once `Operation::RecoverFlow` ran for the flow here.
+    pub(crate) async fn ensure_pending(&mut self, m4: &M4) -> Result<(), Error> {
+        match self.flows.get(&m4.flow_id).await? {
+            Some(flow) if flow.outcome == Some(FlowOutcome::Err(ErrorCode::TimedOut)) => Err(Error::FlowRecoveredError),
+            _ => Ok(()),
+        }
+    }
+
+    /// `Operation::RecoverFlow`, on the chain the flow started on or one that gave a token up.
+    pub(crate) async fn recover_flow(&mut self, flow_id: FlowId) -> Result<ExecutionResult<Message>, Error> {
+        let mut flow = self.flows.get(&flow_id).await?.ok_or(Error::FlowNotFoundError)?;
+        if flow.outcome.is_some() {
+            return Err(Error::FlowNotPendingError);
+        }
+        let now = system_api::current_system_time();
+        if !flow.recoverable(now) {
+            return Err(Error::FlowNotExpiredError);
+        }
+        let mut result = ExecutionResult::default();
+        for compensation in flow.compensations.drain(..).rev() {
+            match compensation {
+                Compensation::RefundEscrow { token_id, amount } => self.refund_escrow(token_id, amount).await?,
+                Compensation::RestoreToken { token } => {
+                    let minted_chain = token.id.minted_chain;
+                    let message = Message::RecoverToken { flow_id: flow_id.clone(), token };
+                    result = result.with_authenticated_message(minted_chain, message);
+                }
+                Compensation::RestoreSentToken { token, recipient } => {
+                    let message = Message::ReturnToken { flow_id: flow_id.clone(), token };
+                    result = result.with_authenticated_message(recipient, message);
+                }
+            }
+        }
+        flow.step = "RecoverFlow".to_string();
+        flow.updated = now;
+        flow.outcome = Some(FlowOutcome::Err(ErrorCode::TimedOut));
+        self.flows.insert(&flow_id, flow)?;
+        self.pending_flow_ids.remove(&flow_id)?;
+        Ok(result)
+    }
+
+    /// Minting chain side of a recovered flow: hands `token` back to `owner` if it still is
+    /// the owner on record, and fences the flow so none of its later steps runs here.
+    pub(crate) async fn recover_token(
+        &mut self,
+        flow_id: FlowId,
+        token: Token,
+        owner: ChainId,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        if self.current_owner_minted.get(&token.id).await? != Some(owner) {
+            info!("{:?} moved on from {}, not recovered", token.id, owner);
+            return Ok(ExecutionResult::default());
+        }
+        self.last_steps.insert(&flow_id, FENCED_STEP)?;
+        Ok(ExecutionResult::default().with_authenticated_message(owner, Message::TokenRecovered { token }))
+    }
+
+    /// Recipient side of a recovered flow: fences the flow so a transfer still in flight never
+    /// lands here, and gives the token up if it arrived but the flow did not finish here. The
+    /// minting chain then decides who owns it. A recipient that started the flow finished it
+    /// once the flow ended well, it is not fenced when the token stays.
+    pub(crate) async fn return_token(
+        &mut self,
+        flow_id: FlowId,
+        token: Token,
+        owner: ChainId,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        let succeeded = matches!(
+            self.flows.get(&flow_id).await?,
+            Some(FlowState { outcome: Some(FlowOutcome::Ok(_)), .. })
+        );
+        let finished = succeeded || self.last_steps.get(&flow_id).await? == Some(FENCED_STEP);
+        self.last_steps.insert(&flow_id, FENCED_STEP)?;
+        let returned = if finished || !self.check_own(token.id.clone()).await? {
+            false
+        } else {
+            match self.transfer_from_me(token.id.clone()).await {
+                Ok(_) => true,
+                Err(error) => {
+                    info!("{:?} not returned: {:?}", token.id, error);
+                    false
+                }
+            }
+        };
+        let minted_chain = token.id.minted_chain;
+        let message = Message::RecoverReturnedToken { flow_id, token, owner, returned };
+        Ok(ExecutionResult::default().with_authenticated_message(minted_chain, message))
+    }
+
+    /// Minting chain side of a recovered flow whose token was sent to `recipient`: hands the
+    /// token back to `owner` if `owner` still is the owner on record, or `recipient` is and gave
+    /// it up, and fences the flow. A token given up for nothing goes back to `recipient`.
+    pub(crate) async fn recover_returned_token(
+        &mut self,
+        flow_id: FlowId,
+        token: Token,
+        owner: ChainId,
+        recipient: ChainId,
+        returned: bool,
+    ) -> Result<ExecutionResult<Message>, Error> {
+        let current = self.current_owner_minted.get(&token.id).await?;
+        if current != Some(owner) && !(returned && current == Some(recipient)) {
+            info!("{:?} moved on from {}, not recovered", token.id, owner);
+            if !returned {
+                return Ok(ExecutionResult::default());
+            }
+            return Ok(ExecutionResult::default().with_authenticated_message(recipient, Message::TokenRecovered { token }));
+        }
+        self.last_steps.insert(&flow_id, FENCED_STEP)?;
+        if current == Some(recipient) {
+            self.transfer_update_minted(token.id.clone(), owner, recipient).await?;
+        }
+        Ok(ExecutionResult::default().with_authenticated_message(owner, Message::TokenRecovered { token }))
+    }
+}
@@ -0,0 +1,111 @@
+//! Where `Operation::Receive` looks up the income a stream pays a share of, picked by
+//! `Parameter::revenue_source` when the application is created. Shared by the contract,
+//! which pays that income out, and the service, which previews it.
+
+use crate::state::Aqueduct;
+use aqueduct::{LoggedFungibleOperation, Operation, RevenueSourceKind};
+use async_trait::async_trait;
+use linera_sdk::{
+    base::{Amount, ApplicationId, ChainId, Timestamp},
+    views::views::ViewError,
+};
+
+/// What revenue sources read: the application state, and the logger, which the contract
+/// calls and the service queries.
+#[async_trait]
+pub trait RevenueBackend: Send {
+    type Error: From<ViewError> + From<bcs::Error> + From<hex::FromHexError> + Send;
+
+    fn state(&self) -> &Aqueduct;
+
+    /// Logs the logger saw for `keyword` (and `app`, if given) between `start` and `end`.
+    async fn query_logger(
+        &mut self,
+        keyword: &str,
+        app: Option<ApplicationId>,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<String>, Self::Error>;
+}
+
+#[async_trait]
+pub trait RevenueSource<E> {
+    /// Income between `start` and `end` for a stream tracking `keywords`.
+    async fn income(&mut self, keywords: &[String], start: Timestamp, end: Timestamp) -> Result<Amount, E>;
+}
+
+/// The source `kind` names, reading through `backend` for the `company` chain.
+pub fn revenue_source<'a, B: RevenueBackend + 'a>(
+    kind: RevenueSourceKind,
+    company: ChainId,
+    backend: B,
+) -> Result<Box<dyn RevenueSource<B::Error> + Send + 'a>, B::Error> {
+    Ok(match kind {
+        RevenueSourceKind::Logger => Box::new(LoggerRevenue(backend)),
+        RevenueSourceKind::Ledger => Box::new(LedgerRevenue(backend)),
+        RevenueSourceKind::FungibleTransfers { application_id } => Box::new(FungibleTransferRevenue {
+            backend,
+            company,
+            application_id: bcs::from_bytes::<ApplicationId>(&hex::decode(application_id)?)?,
+        }),
+    })
+}
+
+/// Every `Operation::Income` the logger saw, falling back to the ledger for keywords the
+/// logger cannot answer (missing, misconfigured or failing).
+pub struct LoggerRevenue<B>(B);
+
+#[async_trait]
+impl<B: RevenueBackend> RevenueSource<B::Error> for LoggerRevenue<B> {
+    async fn income(&mut self, keywords: &[String], start: Timestamp, end: Timestamp) -> Result<Amount, B::Error> {
+        let mut income = Amount::zero();
+        for keyword in keywords {
+            match self.0.query_logger(keyword, None, start, end).await {
+                Ok(logs) => {
+                    for log in logs {
+                        if let Ok(Operation::Income { amount, .. }) = serde_json::from_str::<Operation>(&log) {
+                            income.saturating_add_assign(amount);
+                        }
+                    }
+                }
+                Err(_) => income.saturating_add_assign(self.0.state().ledger_income(keyword, start, end).await?),
+            }
+        }
+        Ok(income)
+    }
+}
+
+/// Every `Operation::Income` recorded in this chain's `revenue` ledger.
+pub struct LedgerRevenue<B>(B);
+
+#[async_trait]
+impl<B: RevenueBackend> RevenueSource<B::Error> for LedgerRevenue<B> {
+    async fn income(&mut self, keywords: &[String], start: Timestamp, end: Timestamp) -> Result<Amount, B::Error> {
+        let mut income = Amount::zero();
+        for keyword in keywords {
+            income.saturating_add_assign(self.0.state().ledger_income(keyword, start, end).await?);
+        }
+        Ok(income)
+    }
+}
+
+/// Transfers into the company chain made with a fungible token application, as logged by
+/// the logger. Keywords are ignored, every transfer counts once.
+pub struct FungibleTransferRevenue<B> {
+    backend: B,
+    company: ChainId,
+    application_id: ApplicationId,
+}
+
+#[async_trait]
+impl<B: RevenueBackend> RevenueSource<B::Error> for FungibleTransferRevenue<B> {
+    async fn income(&mut self, _keywords: &[String], start: Timestamp, end: Timestamp) -> Result<Amount, B::Error> {
+        let mut income = Amount::zero();
+        for log in self.backend.query_logger("Transfer", Some(self.application_id), start, end).await? {
+            if let Some(amount) = LoggedFungibleOperation::received_by(&log, self.company) {
+                income.saturating_add_assign(amount);
+            }
+        }
+        Ok(income)
+    }
+}
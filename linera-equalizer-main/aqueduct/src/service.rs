@@ -1,15 +1,17 @@
 
 #![cfg_attr(target_arch = "wasm32", no_main)]
 
+mod revenue;
 mod state;
 
+use self::revenue::{RevenueBackend, RevenueSource};
 use self::state::Aqueduct;
 use async_trait::async_trait;
-use linera_sdk::{base::{WithServiceAbi, Amount}, QueryContext, Service, ViewStateStorage};
+use linera_sdk::{base::{WithServiceAbi, Amount, ApplicationId, ChainId, Timestamp}, service::system_api, QueryContext, Service, ViewStateStorage};
 use std::sync::Arc;
 use thiserror::Error;
 use aqueduct::*;
-use async_graphql::{Object, Request, Response, Schema, EmptySubscription, EmptyMutation};
+use async_graphql::{Enum, Object, Request, Response, Schema, EmptySubscription, EmptyMutation, MergedObject, SimpleObject, Variables};
 
 linera_sdk::service!(Aqueduct);
 
@@ -27,11 +29,234 @@ impl Service for Aqueduct {
         _context: &QueryContext,
         request: Request,
     ) -> Result<Response, Self::Error> {
-        let schema = Schema::build(self.clone(), MutationRoot {}, EmptySubscription).finish();
+        let schema = Schema::build(
+            QueryRoot(self.clone(), Queries { state: self.clone() }),
+            MutationRoot {},
+            EmptySubscription,
+        )
+        .finish();
         let response = schema.execute(request).await;
         Ok(response)
     }
 }
+
+/// The views themselves plus the computed queries in `Queries`.
+#[derive(MergedObject)]
+struct QueryRoot(Arc<Aqueduct>, Queries);
+
+struct Queries {
+    state: Arc<Aqueduct>,
+}
+
+const MARKETPLACE_PAGE_SIZE: usize = 20;
+
+/// One period `Operation::Receive` would pay out.
+#[derive(SimpleObject)]
+struct PayoutPreview {
+    period_start: Timestamp,
+    period_end: Timestamp,
+    income: Amount,
+    multiplier: String,
+    payout: Amount,
+    total: Amount,  //running total including this period
+}
+
+/// Order of `Queries::marketplace` results, ties broken by mint order.
+#[derive(Clone, Copy, PartialEq, Eq, Enum)]
+enum MarketSort {
+    PriceAsc,
+    PriceDesc,
+    Newest,
+    Oldest,
+}
+
+#[derive(SimpleObject)]
+struct MarketEntry {
+    token_id: TokenId,
+    seller: ChainId,
+    price: Amount,
+}
+
+#[derive(SimpleObject)]
+struct MarketPage {
+    entries: Vec<MarketEntry>,
+    total: usize,   //matching listings over all pages
+}
+
+#[derive(SimpleObject)]
+struct TokenListing {
+    token_id: TokenId,
+    listing: Listing,
+}
+
+#[derive(SimpleObject)]
+struct ScheduleReport {
+    valid: bool,
+    error: Option<String>,
+}
+
+#[Object]
+impl Queries {
+    /// Runs the same checks as `Operation::Mint` without submitting anything.
+    async fn validate_schedule(
+        &self,
+        segments: Vec<Segment>,
+    ) -> ScheduleReport {
+        match validate_schedule(&segments) {
+            Ok(()) => ScheduleReport { valid: true, error: None },
+            Err(error) => ScheduleReport { valid: false, error: Some(error.to_string()) },
+        }
+    }
+
+    /// Flows started on this chain that have not reported an outcome yet, oldest first.
+    async fn pending_flows(&self) -> async_graphql::Result<Vec<FlowState>> {
+        let mut flows = Vec::new();
+        for id in self.state.pending_flows().await? {
+            if let Some(flow) = self.state.flows.get(&id).await? {
+                flows.push(flow);
+            }
+        }
+        Ok(flows)
+    }
+
+    /// The `nfts`, `ownedTokens` and `listings` views are only rewritten by the contract's
+    /// migration, these read them the way the current schema expects even before it ran.
+    async fn stream(&self, stream_id: StreamId) -> async_graphql::Result<Option<Stream>> {
+        Ok(self.state.stream(&stream_id).await?)
+    }
+
+    async fn tokens(&self) -> async_graphql::Result<Vec<Token>> {
+        Ok(self.state.tokens().await?)
+    }
+
+    async fn token_listings(&self) -> async_graphql::Result<Vec<TokenListing>> {
+        Ok(self.state.token_listings().await?.into_iter().map(|(token_id, listing)| TokenListing { token_id, listing }).collect())
+    }
+
+    async fn flow(&self, id: FlowId) -> async_graphql::Result<Option<FlowState>> {
+        Ok(self.state.flows.get(&id).await?)
+    }
+
+    /// Active listings of the tokens `company` minted, as mirrored to this chain. Only
+    /// meaningful when asked of `company` itself.
+    async fn marketplace(
+        &self,
+        company: ChainId,
+        sort_by: Option<MarketSort>,
+        min_price: Option<Amount>,
+        max_price: Option<Amount>,
+        offset: Option<usize>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<MarketPage> {
+        let mut entries = Vec::new();
+        for token_id in self.state.market.indices().await? {
+            if token_id.minted_chain != company {
+                continue;
+            }
+            if let Some(MarketListing { seller, price }) = self.state.market.get(&token_id).await? {
+                if min_price.map_or(true, |min| price >= min) && max_price.map_or(true, |max| price <= max) {
+                    entries.push(MarketEntry { token_id, seller, price });
+                }
+            }
+        }
+        match sort_by.unwrap_or(MarketSort::Newest) {
+            MarketSort::PriceAsc => entries.sort_by_key(|entry| (entry.price, entry.token_id.index)),
+            MarketSort::PriceDesc => entries.sort_by_key(|entry| (std::cmp::Reverse(entry.price), entry.token_id.index)),
+            MarketSort::Newest => entries.sort_by_key(|entry| std::cmp::Reverse(entry.token_id.index)),
+            MarketSort::Oldest => entries.sort_by_key(|entry| entry.token_id.index),
+        }
+        let total = entries.len();
+        let entries = entries
+            .into_iter()
+            .skip(offset.unwrap_or(0))
+            .take(limit.unwrap_or(MARKETPLACE_PAGE_SIZE))
+            .collect();
+        Ok(MarketPage { entries, total })
+    }
+
+    /// What `Operation::Receive` would pay if the company handled it at `until` (now if
+    /// omitted). Has to be asked of the company chain, the only one storing the stream.
+    async fn preview_receive(
+        &self,
+        stream_id: StreamId,
+        until: Option<Timestamp>,
+    ) -> async_graphql::Result<Vec<PayoutPreview>> {
+        let stream = self.state.stream(&stream_id).await?.ok_or(Error::CompanyDoesntHaveStreamError)?;
+        let until = until.unwrap_or_else(system_api::current_system_time);
+        let due = stream.due_periods(StreamTime::from_absolute(until, stream.created))?;
+        let mut total = Amount::zero();
+        let mut previews = Vec::new();
+        for period in due.periods {
+            let period_start = period.start.to_absolute(stream.created);
+            let period_end = period.end.to_absolute(stream.created);
+            let income = self.income(&stream.keywords, period_start, period_end).await?;
+            let payout = payout_share(income, period.multiplier)?;
+            total.saturating_add_assign(payout);
+            previews.push(PayoutPreview {
+                period_start,
+                period_end,
+                income,
+                multiplier: fixed::to_decimal(period.multiplier),
+                payout,
+                total,
+            });
+        }
+        Ok(previews)
+    }
+}
+
+impl Queries {
+    /// Same lookup as the contract's, the `RevenueSource` picked by `Parameter::revenue_source`.
+    async fn income(&self, keywords: &[String], start: Timestamp, end: Timestamp) -> Result<Amount, Error> {
+        revenue::revenue_source(Aqueduct::parameters()?.revenue_source, system_api::current_chain_id(), &*self.state)?
+            .income(keywords, start, end)
+            .await
+    }
+}
+
+#[async_trait]
+impl RevenueBackend for &Aqueduct {
+    type Error = Error;
+
+    fn state(&self) -> &Aqueduct {
+        self
+    }
+
+    /// The service side of the `logger::ApplicationCall::Query` made by the contract.
+    async fn query_logger(
+        &mut self,
+        keyword: &str,
+        app: Option<ApplicationId>,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<String>, Error> {
+        let logger_id = bcs::from_bytes::<ApplicationId>(&hex::decode(Aqueduct::parameters()?.logger_application_id)?)?
+            .with_abi::<logger::LoggerAbi>();
+        let request = Request::new(
+            "query Logs($keyword: String!, $app: ApplicationId, $timestamp: [Timestamp!]) { \
+             query(logType: OPERATION_EXECUTION_START, keyword: $keyword, app: $app, timestamp: $timestamp) { log } }",
+        )
+        .variables(Variables::from_json(serde_json::json!({
+            "keyword": keyword,
+            "app": app,
+            "timestamp": [start.micros(), end.micros()],
+        })));
+        let response = system_api::query_application(logger_id, &request)
+            .await
+            .map_err(|error| Error::LoggerQueryError(format!("{:?}", error)))?;
+        if !response.errors.is_empty() {
+            return Err(Error::LoggerQueryError(format!("{:?}", response.errors)));
+        }
+        let data = response.data.into_json()?;
+        Ok(data["query"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|statement| statement["log"].as_str().map(str::to_string))
+            .collect())
+    }
+}
+
 struct MutationRoot;
 
 #[Object]
@@ -42,12 +267,14 @@ impl MutationRoot {
         description: String,
         keywords: Vec<String>,
         segments: Vec<Segment>,
+        royalty_bps: Option<u16>,
     ) -> Vec<u8> {
         bcs::to_bytes(&Operation::Mint {
             name,
             description,
             keywords,
             segments,
+            royalty_bps,
         })
         .unwrap()
     }
@@ -80,6 +307,126 @@ impl MutationRoot {
         })
         .unwrap()
     }
+    async fn retire_stream(
+        &self,
+        stream_id: StreamId,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::RetireStream {
+            stream_id,
+        })
+        .unwrap()
+    }
+    async fn approve(
+        &self,
+        token_id: TokenId,
+        spender: Option<ChainId>,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::Approve {
+            token_id,
+            spender,
+        })
+        .unwrap()
+    }
+    async fn set_approval_for_all(
+        &self,
+        operator: ChainId,
+        approved: bool,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::SetApprovalForAll {
+            operator,
+            approved,
+        })
+        .unwrap()
+    }
+    async fn safe_transfer_from(
+        &self,
+        from: ChainId,
+        to: ChainId,
+        token_id: TokenId,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::SafeTransferFrom {
+            from,
+            to,
+            token_id,
+        })
+        .unwrap()
+    }
+    async fn make_offer(
+        &self,
+        token_id: TokenId,
+        amount: Amount,
+        expires: Timestamp,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::MakeOffer {
+            token_id,
+            amount,
+            expires,
+        })
+        .unwrap()
+    }
+    async fn list_auction(
+        &self,
+        token_id: TokenId,
+        kind: AuctionKind,
+        reserve: Amount,
+        end: Timestamp,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::ListAuction {
+            token_id,
+            kind,
+            reserve,
+            end,
+        })
+        .unwrap()
+    }
+    async fn bid(
+        &self,
+        token_id: TokenId,
+        seller: ChainId,
+        amount: Amount,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::Bid {
+            token_id,
+            seller,
+            amount,
+        })
+        .unwrap()
+    }
+    async fn close_auction(
+        &self,
+        token_id: TokenId,
+        seller: ChainId,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::CloseAuction {
+            token_id,
+            seller,
+        })
+        .unwrap()
+    }
+    async fn accept_offer(
+        &self,
+        token_id: TokenId,
+        amount: Amount,
+        bidder: ChainId,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::AcceptOffer {
+            token_id,
+            amount,
+            bidder,
+        })
+        .unwrap()
+    }
+    async fn withdraw_offer(
+        &self,
+        token_id: TokenId,
+        amount: Amount,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::WithdrawOffer {
+            token_id,
+            amount,
+        })
+        .unwrap()
+    }
     async fn buy(
         &self,
         token_id: TokenId,
@@ -89,6 +436,35 @@ impl MutationRoot {
         })
         .unwrap()
     }
+    async fn transfer(
+        &self,
+        token_id: TokenId,
+        to: ChainId,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::Transfer {
+            token_id,
+            to,
+        })
+        .unwrap()
+    }
+    async fn recover_flow(
+        &self,
+        flow_id: FlowId,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::RecoverFlow {
+            flow_id,
+        })
+        .unwrap()
+    }
+    async fn register_receiver(
+        &self,
+        application_id: Option<ApplicationId>,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::RegisterReceiver {
+            application_id,
+        })
+        .unwrap()
+    }
     async fn receive(
         &self,
         token_id: TokenId,
@@ -109,6 +485,26 @@ impl MutationRoot {
         })
         .unwrap()
     }
+    async fn deposit(
+        &self,
+        amount: Amount,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::Deposit {
+            amount,
+        })
+        .unwrap()
+    }
+    async fn withdraw(
+        &self,
+        amount: Amount,
+        to: Beneficiary,
+    ) -> Vec<u8> {
+        bcs::to_bytes(&Operation::Withdraw {
+            amount,
+            to,
+        })
+        .unwrap()
+    }
 }
 
 /// An error that can occur while querying the service.
@@ -122,5 +518,20 @@ pub enum Error {
     #[error("Invalid query argument; could not deserialize request")]
     InvalidQuery(#[from] serde_json::Error),
 
+    #[error("Failed to deserialize BCS bytes {0}")]
+    BcsError(#[from] bcs::Error),
+
+    #[error("hecks {0}")]
+    HexError(#[from] hex::FromHexError),
+
+    #[error("company did not mint a nft with this streamid")]
+    CompanyDoesntHaveStreamError,
+
+    #[error("logger query failed {0}")]
+    LoggerQueryError(String),
+
+    #[error("view error {0}")]
+    ViewError(#[from] linera_sdk::views::views::ViewError),
+
     // Add error variants here.
 }
@@ -1,17 +1,41 @@
 use linera_sdk::views::{ViewStorageContext, RegisterView, MapView, SetView};
 use linera_views::views::{GraphQLView, RootView};
-use linera_sdk::base::{ChainId, Amount};
-use aqueduct::{TokenId, Token, StreamId, Stream};
+use linera_sdk::base::{ApplicationId, ChainId, Amount, Timestamp};
+use linera_sdk::views::views::ViewError;
+use aqueduct::{TokenId, Token, TokenMetadata, FlowId, FlowState, Listing, MarketListing, Offer, Settlement, StreamId, Stream, StreamTime, LegacyStream, Payout, RevenueKey};
+
+/// Bumped whenever stored data needs rewriting, see `Aqueduct::migrate` in the contract.
+pub const SCHEMA_VERSION: u32 = 5;
 
 #[derive(RootView, GraphQLView)]
 #[view(context = "ViewStorageContext")]
 pub struct Aqueduct {
-    pub nfts: MapView<StreamId, Stream>,
-    pub listings: MapView<TokenId, Amount>,
+    pub legacy_nfts: MapView<StreamId, LegacyStream>,   //streams minted before payout curves, emptied by migration
+    pub legacy_listings: MapView<TokenId, Amount>,   //fixed prices before auctions, emptied by migration
     pub number_minted: RegisterView<u64>,
     pub current_owner_minted: MapView<TokenId, ChainId>,
-    pub owned_tokens: SetView<Token>,
+    pub legacy_owned_tokens: SetView<Token>,   //tokens before they were keyed by id, emptied by migration
     pub balance: RegisterView<Amount>,
+    pub nfts: MapView<StreamId, Stream>,
+    pub schema_version: RegisterView<u32>,
+    pub arrears: MapView<StreamId, Amount>,         //company side, owed to holders but not paid yet
+    pub last_payouts: MapView<TokenId, Payout>,     //holder side, latest payout per token
+    pub revenue: MapView<RevenueKey, Amount>,       //company side, every Operation::Income
+    pub owned_tokens: MapView<TokenId, TokenMetadata>,
+    pub burned_streams: SetView<StreamId>,           //company side, streams whose token was burned
+    pub market: MapView<TokenId, MarketListing>,    //minting side, active listings of the tokens minted here
+    pub offers: MapView<TokenId, Offer>,            //minting side, best live offer per token minted here
+    pub escrow: MapView<TokenId, Amount>,           //bidder side, held back from balance for own offers and bids
+    pub listings: MapView<TokenId, Listing>,
+    pub royalties: MapView<TokenId, u16>,            //minting side, royalty basis points per token minted here
+    pub token_approvals: MapView<TokenId, ChainId>,  //owner side, the one chain allowed to move a token
+    pub operators: SetView<ChainId>,                 //owner side, chains allowed to move every token
+    pub receiver: RegisterView<Option<ApplicationId>>,  //asked to acknowledge every token arriving here
+    pub flow_count: RegisterView<u64>,
+    pub flows: MapView<FlowId, FlowState>,           //flows started on this chain or that took a token from it
+    pub last_steps: MapView<FlowId, u64>,            //latest step handled on this chain per flow
+    pub settlements: MapView<TokenId, Settlement>,   //bidder side, escrow promised to a seller until the token arrives
+    pub pending_flow_ids: SetView<FlowId>,           //flows in `flows` without an outcome yet
 }
 
 impl Aqueduct {
@@ -20,4 +44,91 @@ impl Aqueduct {
             .get()
     }
 
+    /// Income recorded on this chain for `keyword` in the buckets starting within `[start, end)`.
+    pub(crate) async fn ledger_income(&self, keyword: &str, start: Timestamp, end: Timestamp) -> Result<Amount, ViewError> {
+        let mut income = Amount::zero();
+        for key in RevenueKey::range(keyword, start, end) {
+            if let Some(amount) = self.revenue.get(&key).await? {
+                income.saturating_add_assign(amount);
+            }
+        }
+        Ok(income)
+    }
+
+    /// Ids of the streams minted here, read from wherever the current schema version keeps them.
+    pub(crate) async fn stream_ids(&self) -> Result<Vec<StreamId>, ViewError> {
+        if *self.schema_version.get() < 1 {
+            self.legacy_nfts.indices().await
+        } else {
+            self.nfts.indices().await
+        }
+    }
+
+    /// `nfts[stream_id]` as `migrate` in the contract leaves it. The service cannot migrate,
+    /// so it reads through this until the contract has run once.
+    pub(crate) async fn stream(&self, stream_id: &StreamId) -> Result<Option<Stream>, ViewError> {
+        let version = *self.schema_version.get();
+        if version < 1 {
+            return Ok(self.legacy_nfts.get(stream_id).await?.map(Stream::from));
+        }
+        let mut stream = self.nfts.get(stream_id).await?;
+        if version < 2 {
+            // curve segments still stored period and milestone in milliseconds
+            for segment in stream.iter_mut().flat_map(|stream| &mut stream.segments) {
+                segment.period = StreamTime::from_millis(segment.period.micros());
+                segment.milestone = StreamTime::from_millis(segment.milestone.micros());
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Tokens owned by this chain, from the old token set before schema version 3.
+    pub(crate) async fn tokens(&self) -> Result<Vec<Token>, ViewError> {
+        if *self.schema_version.get() < 3 {
+            return self.legacy_owned_tokens.indices().await;
+        }
+        let mut tokens = Vec::new();
+        for id in self.owned_tokens.indices().await? {
+            if let Some(metadata) = self.owned_tokens.get(&id).await? {
+                tokens.push(Token { id, metadata });
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Ids of the flows kept here that have no outcome yet, oldest first. Before schema
+    /// version 5 they were not indexed and every flow is read.
+    pub(crate) async fn pending_flows(&self) -> Result<Vec<FlowId>, ViewError> {
+        let mut ids = if *self.schema_version.get() < 5 {
+            let mut ids = Vec::new();
+            for id in self.flows.indices().await? {
+                if matches!(self.flows.get(&id).await?, Some(flow) if flow.outcome.is_none()) {
+                    ids.push(id);
+                }
+            }
+            ids
+        } else {
+            self.pending_flow_ids.indices().await?
+        };
+        ids.sort_by_key(|id| id.index);
+        Ok(ids)
+    }
+
+    /// Listings of this chain, the old fixed prices before schema version 4.
+    pub(crate) async fn token_listings(&self) -> Result<Vec<(TokenId, Listing)>, ViewError> {
+        let legacy = *self.schema_version.get() < 4;
+        let mut listings = Vec::new();
+        let token_ids = if legacy { self.legacy_listings.indices().await? } else { self.listings.indices().await? };
+        for token_id in token_ids {
+            let listing = if legacy {
+                self.legacy_listings.get(&token_id).await?.map(|price| Listing::FixedPrice { price })
+            } else {
+                self.listings.get(&token_id).await?
+            };
+            if let Some(listing) = listing {
+                listings.push((token_id, listing));
+            }
+        }
+        Ok(listings)
+    }
 }
@@ -0,0 +1,83 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::{Auction, AuctionError, AuctionKind, Bid};
+use linera_sdk::base::{Amount, ChainId, Timestamp};
+
+fn bid(bidder: ChainId, tokens: u128) -> Bid {
+    Bid {
+        bidder,
+        amount: Amount::from_tokens(tokens),
+    }
+}
+
+fn at(micros: u64) -> Timestamp {
+    Timestamp::from(micros)
+}
+
+#[test]
+fn english_auctions_keep_the_highest_bid() {
+    let (alice, bob) = (ChainId::root(1), ChainId::root(2));
+    let mut auction = Auction::new(
+        AuctionKind::English,
+        Amount::from_tokens(10),
+        at(0),
+        at(100),
+    )
+    .unwrap();
+    assert_eq!(
+        auction.place(bid(alice, 9), at(10)),
+        Err(AuctionError::BelowReserve)
+    );
+    assert_eq!(auction.place(bid(alice, 10), at(10)), Ok(None));
+    assert_eq!(
+        auction.place(bid(bob, 10), at(20)),
+        Err(AuctionError::NotAboveBestBid)
+    );
+    assert_eq!(
+        auction.place(bid(bob, 12), at(20)),
+        Ok(Some(bid(alice, 10)))
+    );
+    assert_eq!(auction.close(at(99)), Err(AuctionError::StillRunning));
+    assert_eq!(
+        auction.place(bid(alice, 20), at(100)),
+        Err(AuctionError::Ended)
+    );
+    assert_eq!(auction.close(at(100)), Ok(Some(bid(bob, 12))));
+}
+
+#[test]
+fn dutch_auctions_decline_to_the_reserve() {
+    let (alice, bob) = (ChainId::root(1), ChainId::root(2));
+    let kind = AuctionKind::Dutch {
+        start_price: Amount::from_tokens(100),
+    };
+    assert_eq!(
+        Auction::new(kind.clone(), Amount::from_tokens(101), at(0), at(100)),
+        Err(AuctionError::StartBelowReserve)
+    );
+    let mut auction = Auction::new(kind, Amount::from_tokens(20), at(0), at(100)).unwrap();
+    assert_eq!(auction.price_at(at(0)), Amount::from_tokens(100));
+    assert_eq!(auction.price_at(at(25)), Amount::from_tokens(80));
+    assert_eq!(auction.price_at(at(1_000)), Amount::from_tokens(20));
+    assert_eq!(
+        auction.place(bid(alice, 70), at(25)),
+        Err(AuctionError::BelowCurrentPrice)
+    );
+    assert_eq!(auction.place(bid(alice, 80), at(25)), Ok(None));
+    assert_eq!(
+        auction.place(bid(bob, 90), at(26)),
+        Err(AuctionError::AlreadySold)
+    );
+    // sold before the end, so it can be closed right away
+    assert_eq!(auction.close(at(30)), Ok(Some(bid(alice, 80))));
+}
+
+#[test]
+fn auctions_without_bids_close_empty() {
+    assert_eq!(
+        Auction::new(AuctionKind::English, Amount::zero(), at(10), at(10)),
+        Err(AuctionError::EndsBeforeStart)
+    );
+    let auction = Auction::new(AuctionKind::English, Amount::zero(), at(0), at(10)).unwrap();
+    assert_eq!(auction.close(at(10)), Ok(None));
+}
@@ -0,0 +1,118 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::{
+    checked_debit, native_deposit, plan_withdrawal, royalty_share, Beneficiary,
+    InsufficientBalance, Withdrawal, WithdrawalError, MAX_ROYALTY_BPS,
+};
+use linera_sdk::base::{Amount, ChainId, Owner};
+
+#[test]
+fn debits_within_the_balance_succeed() {
+    assert_eq!(
+        checked_debit(Amount::from_tokens(5), Amount::from_tokens(2)),
+        Ok(Amount::from_tokens(3))
+    );
+    assert_eq!(
+        checked_debit(Amount::from_tokens(5), Amount::from_tokens(5)),
+        Ok(Amount::zero())
+    );
+}
+
+#[test]
+fn withdrawing_more_than_the_balance_fails() {
+    assert_eq!(
+        checked_debit(
+            Amount::from_tokens(5),
+            Amount::from_atto(5_000_000_000_000_000_001)
+        ),
+        Err(InsufficientBalance {
+            requested: Amount::from_atto(5_000_000_000_000_000_001),
+            available: Amount::from_tokens(5),
+        })
+    );
+    assert!(checked_debit(Amount::zero(), Amount::from_atto(1)).is_err());
+}
+
+#[test]
+fn native_deposits_only_book_unbooked_chain_tokens() {
+    let booked = Amount::from_tokens(4);
+    let chain = Amount::from_tokens(10);
+    assert_eq!(
+        native_deposit(booked, chain, Amount::from_tokens(6)),
+        Ok(Amount::from_tokens(10))
+    );
+    assert_eq!(
+        native_deposit(booked, chain, Amount::from_tokens(7)),
+        Err(InsufficientBalance {
+            requested: Amount::from_tokens(7),
+            available: Amount::from_tokens(6),
+        })
+    );
+    // a chain that spent tokens the application booked has nothing left to deposit
+    assert!(native_deposit(booked, Amount::from_tokens(3), Amount::from_atto(1)).is_err());
+}
+
+#[test]
+fn withdrawals_check_balance_beneficiary_and_owner() {
+    let here = ChainId::root(0);
+    let there = ChainId::root(1);
+    let signer: Owner = "ab".repeat(32).parse().unwrap();
+    let balance = Amount::from_tokens(5);
+    let plan = |tokens, chain_id, signer, fungible| {
+        let to = Beneficiary {
+            chain_id,
+            owner: None,
+        };
+        plan_withdrawal(
+            balance,
+            Amount::from_tokens(tokens),
+            &to,
+            signer,
+            here,
+            fungible,
+        )
+    };
+    assert_eq!(
+        plan(6, here, None, false),
+        Err(WithdrawalError::InsufficientBalance(InsufficientBalance {
+            requested: Amount::from_tokens(6),
+            available: balance,
+        }))
+    );
+    assert_eq!(
+        plan(2, there, None, false),
+        Err(WithdrawalError::NativeToOtherChain)
+    );
+    assert_eq!(
+        plan(2, here, None, false),
+        Ok(Withdrawal::Native {
+            rest: Amount::from_tokens(3)
+        })
+    );
+    assert_eq!(plan(2, there, None, true), Err(WithdrawalError::NoOwner));
+    // fungible tokens can go to any chain, to the signer unless told otherwise
+    assert_eq!(
+        plan(2, there, Some(signer), true),
+        Ok(Withdrawal::Fungible {
+            owner: signer,
+            rest: Amount::from_tokens(3)
+        })
+    );
+}
+
+#[test]
+fn royalties_round_down_in_basis_points() {
+    assert_eq!(
+        royalty_share(Amount::from_tokens(10), 250),
+        Amount::from_atto(250_000_000_000_000_000)
+    );
+    assert_eq!(royalty_share(Amount::from_atto(9_999), 1), Amount::zero());
+    assert_eq!(royalty_share(Amount::from_tokens(3), 0), Amount::zero());
+    // capped at the whole price
+    assert_eq!(
+        royalty_share(Amount::from_tokens(3), u16::MAX),
+        Amount::from_tokens(3)
+    );
+    let huge = Amount::from_atto(u128::MAX);
+    assert_eq!(royalty_share(huge, MAX_ROYALTY_BPS), huge);
+}
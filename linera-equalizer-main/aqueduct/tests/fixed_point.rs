@@ -0,0 +1,130 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::fixed::{self, FixedPointError, ONE};
+use aqueduct::{payout_share, PayoutCurve, Segment, StreamTime};
+use linera_sdk::base::Amount;
+use num_bigint::BigUint;
+use num_integer::Roots;
+use num_traits::{Pow, ToPrimitive};
+use proptest::prelude::*;
+
+fn big(x: u128) -> BigUint {
+    BigUint::from(x)
+}
+
+/// floor((x / ONE)^(p / q) * ONE) computed exactly with big integers.
+fn reference_pow(x: u128, p: u32, q: u32) -> u128 {
+    let one = big(ONE as u128);
+    let y = Pow::pow(&big(x), p) * Pow::pow(&one, q) / Pow::pow(&one, p);
+    Roots::nth_root(&y, q).to_u128().unwrap()
+}
+
+fn assert_close(actual: i128, expected: u128) {
+    // the series and the per-step truncation lose a handful of units in the last place,
+    // `exp(y * ln(x))` additionally scales that by the size of the result
+    let tolerance = 1_000 + expected / 1_000_000_000_000_000;
+    let diff = (actual - expected as i128).unsigned_abs();
+    assert!(
+        diff <= tolerance,
+        "actual {} expected {} diff {}",
+        actual,
+        expected,
+        diff
+    );
+}
+
+proptest! {
+    #[test]
+    fn fractional_pow_matches_reference(
+        x in 1..=ONE as u128,
+        // denominators that divide 10^18, so p / q is exact in fixed-point
+        q in prop::sample::select(vec![2u32, 4, 5, 8, 10, 16]),
+        p in 1u32..=24,
+    ) {
+        let exponent = ONE * p as i128 / q as i128;
+        let actual = fixed::pow(x as i128, exponent).unwrap();
+        assert_close(actual, reference_pow(x, p, q));
+    }
+
+    #[test]
+    fn whole_pow_matches_reference(x in 0..=4 * ONE as u128, n in 0u32..=6) {
+        let actual = fixed::pow(x as i128, ONE * n as i128).unwrap();
+        assert_close(actual, reference_pow(x, n, 1));
+    }
+
+    #[test]
+    fn exp_inverts_ln(x in 1_000_000..=100 * ONE as u128) {
+        let actual = fixed::exp(fixed::ln(x as i128).unwrap()).unwrap();
+        assert_close(actual, x);
+    }
+
+    #[test]
+    fn scale_is_exact(amount in any::<u64>(), tokens in 0..1_000_000_000_000u128, multiplier in 0..=100 * ONE as u128) {
+        let amount = tokens * ONE as u128 + amount as u128;
+        let expected = big(amount) * big(multiplier) / big(ONE as u128);
+        prop_assert_eq!(fixed::scale(amount, multiplier).unwrap(), expected.to_u128().unwrap());
+    }
+
+    #[test]
+    fn multiplier_is_monotonic_for_positive_factors(elapsed in 0..=1_000_000u64, exponent in 1..=4 * ONE as i64 / 1_000) {
+        let segment = Segment {
+            period: StreamTime::from_micros(1),
+            milestone: StreamTime::from_micros(1_000_000),
+            curve: PayoutCurve::Power {
+                constant: 0,
+                factor: ONE as i64,
+                exponent: exponent * 1_000,
+            },
+        };
+        let window = StreamTime::from_micros(1_000_000);
+        let now = segment.multiplier(StreamTime::from_micros(elapsed), window).unwrap();
+        let later = segment.multiplier(StreamTime::from_micros((elapsed + 1).min(1_000_000)), window).unwrap();
+        prop_assert!(later + 1_000 >= now);
+    }
+}
+
+#[test]
+fn fractional_multipliers_are_not_truncated() {
+    let segment = Segment {
+        period: StreamTime::from_micros(1),
+        milestone: StreamTime::from_micros(10),
+        curve: PayoutCurve::Power {
+            constant: (ONE / 2) as i64,
+            factor: 0,
+            exponent: ONE as i64,
+        },
+    };
+    let multiplier = segment.multiplier(StreamTime::from_micros(3), StreamTime::from_micros(10)).unwrap();
+    assert_eq!(multiplier, ONE as u128 / 2);
+    assert_eq!(
+        payout_share(Amount::from_tokens(7), multiplier).unwrap(),
+        Amount::from_atto(3_500_000_000_000_000_000)
+    );
+}
+
+#[test]
+fn negative_multipliers_clamp_to_zero() {
+    let segment = Segment {
+        period: StreamTime::from_micros(1),
+        milestone: StreamTime::from_micros(10),
+        curve: PayoutCurve::Power {
+            constant: -(ONE as i64),
+            factor: (ONE / 2) as i64,
+            exponent: ONE as i64,
+        },
+    };
+    assert_eq!(segment.multiplier(StreamTime::from_micros(5), StreamTime::from_micros(10)).unwrap(), 0);
+}
+
+#[test]
+fn edge_cases() {
+    assert_eq!(fixed::pow(0, ONE / 2), Ok(0));
+    assert_eq!(fixed::pow(0, -ONE / 2), Err(FixedPointError::DivisionByZero));
+    assert_eq!(fixed::pow(-ONE, ONE / 2), Err(FixedPointError::NegativeBase));
+    assert_eq!(fixed::pow(-2 * ONE, 3 * ONE), Ok(-8 * ONE));
+    assert_eq!(fixed::pow(2 * ONE, -ONE), Ok(ONE / 2));
+    assert_eq!(fixed::ratio(1, 0), Err(FixedPointError::DivisionByZero));
+    assert_eq!(fixed::exp(200 * ONE), Err(FixedPointError::Overflow));
+    assert_eq!(fixed::exp(-200 * ONE), Ok(0));
+    assert_eq!(fixed::scale(u128::MAX, 2 * ONE as u128), Err(FixedPointError::Overflow));
+}
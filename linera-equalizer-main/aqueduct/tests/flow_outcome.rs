@@ -0,0 +1,48 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::{
+    ErrorCode, FlowId, FlowKind, FlowOutcome, FlowPayload, FlowState, Payout, PayoutStatus,
+    FLOW_TIMEOUT_MICROS,
+};
+use linera_sdk::base::{Amount, ChainId, Timestamp};
+
+#[test]
+fn only_ok_outcomes_succeed() {
+    let bought = FlowOutcome::Ok(FlowPayload::Bought {
+        price: Amount::from_tokens(10),
+        royalty: Amount::from_tokens(1),
+    });
+    assert!(!bought.is_err());
+    assert_eq!(bought.error(), None);
+    assert!(!FlowOutcome::Ok(FlowPayload::Done).is_err());
+
+    let payout = Payout::new(Amount::from_tokens(5), Amount::from_tokens(3));
+    assert_eq!(payout.status, PayoutStatus::Partial);
+    assert!(!FlowOutcome::Ok(FlowPayload::Payout(payout)).is_err());
+
+    let refused = FlowOutcome::Err(ErrorCode::Refused);
+    assert!(refused.is_err());
+    assert_eq!(refused.error(), Some(ErrorCode::Refused));
+}
+
+#[test]
+fn failed_rollbacks_report_what_failed_the_flow() {
+    let outcome = FlowOutcome::RollbackFailed(ErrorCode::InsufficientBalance, ErrorCode::View);
+    assert!(outcome.is_err());
+    assert_eq!(outcome.error(), Some(ErrorCode::InsufficientBalance));
+}
+
+#[test]
+fn flows_are_recoverable_only_when_pending_past_their_deadline() {
+    let id = FlowId {
+        chain_id: ChainId::root(1),
+        index: 0,
+    };
+    let mut flow = FlowState::new(id, FlowKind::Buy, "Buy_GetOwner", Timestamp::from(10));
+    assert_eq!(flow.deadline, Timestamp::from(10 + FLOW_TIMEOUT_MICROS));
+    assert!(!flow.recoverable(Timestamp::from(9 + FLOW_TIMEOUT_MICROS)));
+    assert!(flow.recoverable(flow.deadline));
+
+    flow.outcome = Some(FlowOutcome::Ok(FlowPayload::Done));
+    assert!(!flow.recoverable(flow.deadline));
+}
@@ -0,0 +1,33 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::{classify_step, StepCheck, FENCED_STEP};
+
+#[test]
+fn first_and_later_steps_run() {
+    assert_eq!(classify_step(None, 0), StepCheck::Fresh);
+    assert_eq!(classify_step(None, 3), StepCheck::Fresh);
+    assert_eq!(classify_step(Some(3), 4), StepCheck::Fresh);
+    assert_eq!(classify_step(Some(3), 7), StepCheck::Fresh);
+}
+
+#[test]
+fn redelivered_steps_are_replays() {
+    assert_eq!(classify_step(Some(0), 0), StepCheck::Replayed);
+    assert_eq!(classify_step(Some(4), 4), StepCheck::Replayed);
+}
+
+#[test]
+fn steps_behind_a_handled_one_are_replays() {
+    assert_eq!(classify_step(Some(4), 2), StepCheck::Replayed);
+    assert_eq!(classify_step(Some(4), 0), StepCheck::Replayed);
+}
+
+#[test]
+fn recovered_flows_run_no_step() {
+    assert_eq!(classify_step(Some(FENCED_STEP), 0), StepCheck::Fenced);
+    assert_eq!(classify_step(Some(FENCED_STEP), 5), StepCheck::Fenced);
+    assert_eq!(
+        classify_step(Some(FENCED_STEP), FENCED_STEP),
+        StepCheck::Fenced
+    );
+}
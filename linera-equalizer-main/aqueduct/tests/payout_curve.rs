@@ -0,0 +1,108 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::fixed::ONE;
+use aqueduct::{validate_schedule, CurveStep, PayoutCurve, ScheduleError, Segment, StreamTime};
+
+const HALF: i64 = (ONE / 2) as i64;
+const FULL: i64 = ONE as i64;
+
+#[test]
+fn legacy_json_segments_become_power_curves_in_microseconds() {
+    let json = r#"[{"period":10,"constant":1,"factor":2,"exponent":3,"milestone":100}]"#;
+    let segments: Vec<Segment> = serde_json::from_str(json).unwrap();
+    assert_eq!(segments[0].period, StreamTime::from_millis(10));
+    assert_eq!(segments[0].milestone, StreamTime::from_micros(100_000));
+    assert_eq!(
+        segments[0].curve,
+        PayoutCurve::Power { constant: 1, factor: 2, exponent: 3 }
+    );
+}
+
+#[test]
+fn curve_segments_name_microseconds_in_json() {
+    let json = r#"{"period_micros":10,"milestone_micros":100,"curve":{"Constant":{"value":1}}}"#;
+    let segment: Segment = serde_json::from_str(json).unwrap();
+    assert_eq!(segment.period, StreamTime::from_micros(10));
+    assert_eq!(segment.milestone, StreamTime::from_micros(100));
+    assert!(serde_json::to_string(&segment).unwrap().starts_with(r#"{"period_micros":10,"milestone_micros":100,"#));
+}
+
+#[test]
+fn curve_segments_round_trip_through_json_and_bcs() {
+    let segment = Segment {
+        period: StreamTime::from_micros(10),
+        milestone: StreamTime::from_micros(100),
+        curve: PayoutCurve::Capped {
+            cap: HALF,
+            curve: Box::new(PayoutCurve::Linear { start: 0, end: FULL }),
+        },
+    };
+    let json: Segment = serde_json::from_str(&serde_json::to_string(&segment).unwrap()).unwrap();
+    assert_eq!(json.curve, segment.curve);
+    let bcs: Segment = bcs::from_bytes(&bcs::to_bytes(&segment).unwrap()).unwrap();
+    assert_eq!(bcs.curve, segment.curve);
+}
+
+#[test]
+fn curves_evaluate() {
+    let quarter = ONE / 4;
+    assert_eq!(PayoutCurve::Constant { value: HALF }.evaluate(quarter), Ok(ONE / 2));
+    assert_eq!(
+        PayoutCurve::Linear { start: 0, end: FULL }.evaluate(quarter),
+        Ok(quarter)
+    );
+    let steps = PayoutCurve::Step {
+        steps: vec![
+            CurveStep { from: HALF, value: FULL },
+            CurveStep { from: 0, value: HALF },
+        ],
+    };
+    assert_eq!(steps.evaluate(quarter), Ok(ONE / 2));
+    assert_eq!(steps.evaluate(ONE), Ok(ONE));
+    let vesting = PayoutCurve::Cliff {
+        at: HALF,
+        curve: Box::new(PayoutCurve::Linear { start: 0, end: FULL }),
+    };
+    assert_eq!(vesting.evaluate(quarter), Ok(0));
+    assert_eq!(vesting.evaluate(3 * quarter), Ok(3 * quarter));
+    let capped = PayoutCurve::Capped {
+        cap: HALF,
+        curve: Box::new(PayoutCurve::Linear { start: 0, end: FULL }),
+    };
+    assert_eq!(capped.evaluate(3 * quarter), Ok(ONE / 2));
+    let decay = PayoutCurve::Decay { initial: FULL, rate: FULL };
+    assert_eq!(decay.evaluate(0), Ok(ONE));
+    assert!((decay.evaluate(ONE).unwrap() - 367_879_441_171_442_321).abs() < 1_000);
+}
+
+#[test]
+fn schedules_are_validated() {
+    let segment = |period, milestone| Segment {
+        period: StreamTime::from_micros(period),
+        milestone: StreamTime::from_micros(milestone),
+        curve: PayoutCurve::Constant { value: FULL },
+    };
+    assert_eq!(validate_schedule(&[]), Err(ScheduleError::EmptySchedule));
+    assert_eq!(
+        validate_schedule(&[segment(10, 100), segment(0, 200)]),
+        Err(ScheduleError::ZeroPeriod(1))
+    );
+    assert_eq!(
+        validate_schedule(&[segment(10, 100), segment(10, 100)]),
+        Err(ScheduleError::NonMonotonicMilestones(1))
+    );
+    assert_eq!(
+        validate_schedule(&[segment(10, 100), segment(150, 200)]),
+        Err(ScheduleError::PeriodExceedsWindow(1))
+    );
+    let unbounded = Segment {
+        period: StreamTime::from_micros(10),
+        milestone: StreamTime::from_micros(100),
+        curve: PayoutCurve::Power { constant: 0, factor: FULL, exponent: -FULL / 2 },
+    };
+    assert!(matches!(
+        validate_schedule(&[unbounded]),
+        Err(ScheduleError::InvalidCurve(0, _))
+    ));
+    assert_eq!(validate_schedule(&[segment(10, 100), segment(100, 200)]), Ok(()));
+}
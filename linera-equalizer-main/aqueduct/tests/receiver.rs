@@ -0,0 +1,20 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::{ReceiverAnswer, ERC721_RECEIVED};
+
+#[test]
+fn chains_without_a_receiver_accept_every_token() {
+    assert!(ReceiverAnswer::Unregistered.accepts());
+}
+
+#[test]
+fn registered_receivers_accept_only_with_the_erc721_answer() {
+    assert!(ReceiverAnswer::Answered(ERC721_RECEIVED).accepts());
+    assert!(!ReceiverAnswer::Answered([0; 4]).accepts());
+    assert!(!ReceiverAnswer::Answered([0x02, 0x7a, 0x0b, 0x15]).accepts());
+}
+
+#[test]
+fn failed_receiver_calls_refuse_the_token() {
+    assert!(!ReceiverAnswer::CallFailed.accepts());
+}
@@ -0,0 +1,38 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use aqueduct::{RevenueKey, REVENUE_BUCKET_MICROS};
+use linera_sdk::base::Timestamp;
+
+const HOUR: u64 = REVENUE_BUCKET_MICROS;
+
+fn buckets(start: u64, end: u64) -> Vec<u64> {
+    RevenueKey::range("sales", Timestamp::from(start), Timestamp::from(end))
+        .map(|key| key.bucket)
+        .collect()
+}
+
+#[test]
+fn windows_count_the_buckets_closing_in_them() {
+    assert_eq!(buckets(0, 3 * HOUR), vec![0, 1, 2]);
+    assert_eq!(buckets(0, 3 * HOUR - 1), vec![0, 1]);
+    assert_eq!(buckets(HOUR / 2, HOUR), vec![0]);
+    assert_eq!(buckets(HOUR / 4, HOUR / 2), Vec::<u64>::new());
+}
+
+#[test]
+fn consecutive_windows_see_every_bucket_once() {
+    let ends = [0, HOUR / 3, HOUR / 2, 2 * HOUR + 7, 5 * HOUR, 8 * HOUR];
+    let seen: Vec<u64> = ends.windows(2).flat_map(|w| buckets(w[0], w[1])).collect();
+    assert_eq!(seen, (0..8).collect::<Vec<u64>>());
+}
+
+#[test]
+fn income_after_a_payout_in_the_same_bucket_counts_in_the_next_period() {
+    // a stream created mid-bucket pays its first period out at its end, while that bucket is open
+    let created = 10 * HOUR + HOUR / 3;
+    let period_end = created + HOUR / 2;
+    let paid = buckets(created, period_end);
+    let late = RevenueKey::at("sales".to_string(), Timestamp::from(period_end + 1));
+    assert!(!paid.contains(&late.bucket));
+    assert!(buckets(period_end, period_end + HOUR).contains(&late.bucket));
+}